@@ -0,0 +1,142 @@
+//! Login throughput with Argon2 verification run inline on the async
+//! executor (how handlers used to call `hash::verify_password`) versus
+//! through `hash::Hasher`, the pool the server verifies passwords on now.
+//! The pool is measured with and without a pepper, and with a single worker
+//! and a short queue timeout to exercise the `HashError::Busy` path.
+//!
+//! Each login verifies one password. While the logins run, a probe task
+//! measures how long cheap requests wait for an executor thread.
+//!
+//! Run with `cargo bench --bench login_throughput`.
+
+// Only the hashing half of these modules is used here.
+#[allow(dead_code)]
+#[path = "../src/hash.rs"]
+mod hash;
+#[allow(dead_code)]
+#[path = "../src/settings.rs"]
+mod settings;
+
+use hash::{verify_password, HashError, Hasher};
+use rocket::tokio::{
+    runtime, task,
+    time::{sleep, Duration, Instant},
+};
+use settings::Settings;
+use std::{env, fs, sync::Arc};
+
+const LOGINS: usize = 64;
+const EXECUTOR_THREADS: usize = 4;
+const PROBES: usize = 200;
+/// Long enough that only the saturated run ever turns a login away.
+const PATIENT_QUEUE_TIMEOUT_MS: u64 = 120_000;
+
+/// How each login verifies its password.
+#[derive(Clone, Copy)]
+enum Mode {
+    /// `hash::verify_password` on the executor thread, as before the pool.
+    Inline,
+    /// `Hasher::verify` on its bounded blocking pool.
+    Pooled,
+}
+
+struct Outcome {
+    throughput: f64,
+    busy: usize,
+    p50: Duration,
+    p99: Duration,
+}
+
+async fn probe_latency() -> Vec<Duration> {
+    let mut latencies = Vec::with_capacity(PROBES);
+    for _ in 0..PROBES {
+        let queued = Instant::now();
+        let scheduled = task::spawn(async move { queued.elapsed() }).await.unwrap();
+        latencies.push(scheduled);
+        sleep(Duration::from_millis(1)).await;
+    }
+    latencies.sort();
+    latencies
+}
+
+fn run(mode: Mode, settings: &Settings) -> Outcome {
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(EXECUTOR_THREADS)
+        .enable_all()
+        .build()
+        .unwrap();
+    let hasher = Arc::new(Hasher::from_settings(settings).unwrap());
+
+    runtime.block_on(async {
+        let password_hash = hasher.hash("password".to_string()).await.unwrap();
+        let probe = task::spawn(probe_latency());
+        let started = Instant::now();
+        let logins: Vec<_> = (0..LOGINS)
+            .map(|_| {
+                let hasher = hasher.clone();
+                let password_hash = password_hash.clone();
+                task::spawn(async move {
+                    match mode {
+                        Mode::Inline => {
+                            Ok(verify_password("password".to_string(), password_hash).unwrap())
+                        }
+                        Mode::Pooled => hasher.verify("password".to_string(), password_hash).await,
+                    }
+                })
+            })
+            .collect();
+        let mut busy = 0;
+        for handle in logins {
+            match handle.await.unwrap() {
+                Ok(verified) => assert!(verified),
+                Err(HashError::Busy) => busy += 1,
+                Err(err) => panic!("{err}"),
+            }
+        }
+        let elapsed = started.elapsed();
+        let latencies = probe.await.unwrap();
+
+        #[allow(clippy::cast_precision_loss)]
+        let throughput = (LOGINS - busy) as f64 / elapsed.as_secs_f64();
+        Outcome {
+            throughput,
+            busy,
+            p50: latencies[latencies.len() / 2],
+            p99: latencies[latencies.len() * 99 / 100],
+        }
+    })
+}
+
+fn main() {
+    let pepper_file = env::temp_dir().join("rustauth-bench-peppers");
+    fs::write(&pepper_file, "v1=bench-pepper\n").unwrap();
+
+    let pooled = Settings {
+        hash_queue_timeout_ms: Some(PATIENT_QUEUE_TIMEOUT_MS),
+        ..Settings::default()
+    };
+    let peppered = Settings {
+        pepper_file: Some(pepper_file.to_string_lossy().into_owned()),
+        ..pooled.clone()
+    };
+    let saturated = Settings {
+        hash_workers: Some(1),
+        hash_queue_timeout_ms: Some(50),
+        ..Settings::default()
+    };
+
+    println!("{LOGINS} concurrent logins, {EXECUTOR_THREADS} executor threads");
+    for (name, mode, settings) in [
+        ("inline", Mode::Inline, &pooled),
+        ("pooled", Mode::Pooled, &pooled),
+        ("peppered", Mode::Pooled, &peppered),
+        ("saturated", Mode::Pooled, &saturated),
+    ] {
+        let outcome = run(mode, settings);
+        println!(
+            "{name:>9}: {:>7.1} logins/s, {:>2} busy, executor latency p50 {:?}, p99 {:?}",
+            outcome.throughput, outcome.busy, outcome.p50, outcome.p99
+        );
+    }
+    let _ = fs::remove_file(pepper_file);
+}
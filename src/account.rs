@@ -0,0 +1,117 @@
+//! Account lifecycle. Every user is in exactly one status:
+//!
+//! - `pending`: signed up, email not yet verified
+//! - `active`: verified and usable
+//! - `suspended`: blocked by an admin until unsuspended
+//! - `locked`: blocked after too many failed logins, until the lock
+//!   expires or an admin unlocks it
+//! - `deleted`: pending deletion, restorable during the grace period
+//!
+//! Each change is recorded in the user's `status_history` with a reason.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    Pending,
+    Active,
+    Suspended,
+    Locked,
+    Deleted,
+}
+
+impl AccountStatus {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(AccountStatus::Pending),
+            "active" => Some(AccountStatus::Active),
+            "suspended" => Some(AccountStatus::Suspended),
+            "locked" => Some(AccountStatus::Locked),
+            "deleted" => Some(AccountStatus::Deleted),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Pending => "pending",
+            AccountStatus::Active => "active",
+            AccountStatus::Suspended => "suspended",
+            AccountStatus::Locked => "locked",
+            AccountStatus::Deleted => "deleted",
+        }
+    }
+
+    /// The status a usable account returns to, depending on whether its
+    /// email has been verified.
+    pub fn usable(verified: bool) -> Self {
+        if verified {
+            AccountStatus::Active
+        } else {
+            AccountStatus::Pending
+        }
+    }
+
+    /// Whether an account may move from `self` to `to`. Any status but
+    /// `deleted` can be deleted, and restoring returns to the status held
+    /// before deletion.
+    pub fn can_become(self, to: AccountStatus) -> bool {
+        use AccountStatus::{Active, Deleted, Locked, Pending, Suspended};
+        match (self, to) {
+            (Deleted, Deleted) => false,
+            (_, Deleted)
+            | (Deleted, _)
+            | (Pending, Active | Suspended | Locked)
+            | (Active, Suspended | Locked)
+            | (Suspended | Locked, Active | Pending)
+            | (Locked, Suspended) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccountStatus::{self, Active, Deleted, Locked, Pending, Suspended};
+
+    const ALL: [AccountStatus; 5] = [Pending, Active, Suspended, Locked, Deleted];
+
+    #[test]
+    fn anything_but_deleted_can_be_deleted_and_restored() {
+        for status in ALL {
+            assert_eq!(status.can_become(Deleted), status != Deleted);
+            assert_eq!(Deleted.can_become(status), status != Deleted);
+        }
+    }
+
+    #[test]
+    fn status_changes() {
+        assert!(Pending.can_become(Active));
+        assert!(Active.can_become(Suspended));
+        assert!(Active.can_become(Locked));
+        assert!(Locked.can_become(Suspended));
+        assert!(Suspended.can_become(Pending));
+        assert!(Locked.can_become(Active));
+
+        assert!(!Active.can_become(Pending));
+        assert!(!Suspended.can_become(Locked));
+        for status in [Pending, Active, Suspended, Locked] {
+            assert!(!status.can_become(status));
+        }
+    }
+
+    #[test]
+    fn usable_status_follows_verification() {
+        assert_eq!(AccountStatus::usable(true), Active);
+        assert_eq!(AccountStatus::usable(false), Pending);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for status in ALL {
+            assert_eq!(AccountStatus::from_name(status.as_str()), Some(status));
+        }
+        assert_eq!(AccountStatus::from_name("banned"), None);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{
+    database::Database,
+    error::ApiError,
+    hash::{to_hex, HashError, Hasher},
+    settings::Settings,
+};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use surrealdb::sql::Datetime;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ROOT_KEY_CACHE_CAPACITY: usize = 1024;
+const ROOT_KEY_CACHE_TTL: Duration = Duration::from_mins(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "sessions:write")]
+    SessionsWrite,
+    #[serde(rename = "keys:admin")]
+    KeysAdmin,
+    #[serde(rename = "emails:admin")]
+    EmailsAdmin,
+    #[serde(rename = "users:admin")]
+    UsersAdmin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::SessionsWrite => "sessions:write",
+            Scope::KeysAdmin => "keys:admin",
+            Scope::EmailsAdmin => "emails:admin",
+            Scope::UsersAdmin => "users:admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The API key presented by a client, read from an `Authorization: Bearer`
+/// or `X-Api-Key` header. The `?key=` query parameter and `api_key` body
+/// fields are only honoured when `legacy_api_key_locations` is enabled.
+pub struct ApiKey {
+    key: Option<String>,
+    allow_legacy: bool,
+}
+
+impl ApiKey {
+    pub fn or_legacy(mut self, legacy_key: Option<String>) -> Self {
+        if self.key.is_none() && self.allow_legacy {
+            self.key = legacy_key;
+        }
+        self
+    }
+
+    pub fn into_inner(self) -> Option<String> {
+        self.key
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let header_key = headers
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| headers.get_one("X-Api-Key"))
+            .map(|key| key.trim().to_string());
+
+        let allow_legacy = request
+            .rocket()
+            .state::<Settings>()
+            .and_then(|settings| settings.legacy_api_key_locations)
+            .unwrap_or(false);
+
+        let key = ApiKey {
+            key: header_key,
+            allow_legacy,
+        };
+        let query_key = request.query_value::<String>("key").and_then(Result::ok);
+        Outcome::Success(key.or_legacy(query_key))
+    }
+}
+
+/// Verifies presented keys without running Argon2 on every request.
+///
+/// Database keys are stored as an HMAC-SHA256 of the full key under the
+/// server's `api_key_secret`, so a lookup by id plus one HMAC authenticates
+/// them. The root key in `Settings` stays an Argon2 hash; successful
+/// verifications of it are cached by HMAC digest for a short TTL.
+pub struct KeyVerifier {
+    secret: Vec<u8>,
+    root_key_hash: String,
+    verified_root_keys: Mutex<HashMap<String, Instant>>,
+}
+
+impl KeyVerifier {
+    pub fn new(secret: &str, root_key_hash: String) -> Self {
+        KeyVerifier {
+            secret: secret.as_bytes().to_vec(),
+            root_key_hash,
+            verified_root_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Generates a new database key, returning its id, the full key to hand
+    /// to the client (`<id>.<secret>`) and the digest to store.
+    pub fn issue(&self) -> (String, String, String) {
+        let mut rng = rand::thread_rng();
+        let mut random_string = |len| {
+            (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(len)
+                .map(char::from)
+                .collect::<String>()
+        };
+        let key_id = random_string(12);
+        let key = format!("{key_id}.{}", random_string(32));
+        let digest = self.digest(&key);
+        (key_id, key, digest)
+    }
+
+    pub fn digest(&self, key: &str) -> String {
+        to_hex(&self.mac(key).finalize().into_bytes())
+    }
+
+    fn mac(&self, key: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(key.as_bytes());
+        mac
+    }
+
+    pub fn matches(&self, key: &str, digest: &str) -> bool {
+        let bytes = (0..digest.len())
+            .step_by(2)
+            .map(|i| {
+                digest
+                    .get(i..i + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>();
+        bytes.is_some_and(|bytes| self.mac(key).verify_slice(&bytes).is_ok())
+    }
+
+    async fn verify_root_key(&self, key: &str, hasher: &Hasher) -> Result<bool, HashError> {
+        let digest = self.digest(key);
+        let cached = self
+            .verified_root_keys
+            .lock()
+            .unwrap()
+            .get(&digest)
+            .is_some_and(|verified_at| verified_at.elapsed() < ROOT_KEY_CACHE_TTL);
+        if cached {
+            return Ok(true);
+        }
+
+        let verified = hasher
+            .verify(key.to_string(), self.root_key_hash.clone())
+            .await?;
+        if !verified {
+            return Ok(false);
+        }
+
+        let mut cache = self.verified_root_keys.lock().unwrap();
+        if cache.len() >= ROOT_KEY_CACHE_CAPACITY {
+            cache.retain(|_, verified_at| verified_at.elapsed() < ROOT_KEY_CACHE_TTL);
+            if cache.len() >= ROOT_KEY_CACHE_CAPACITY {
+                cache.clear();
+            }
+        }
+        cache.insert(digest, Instant::now());
+        Ok(true)
+    }
+}
+
+/// Checks a presented key against the database keys and the root key from
+/// `Settings`. The root key carries every scope; database keys must be
+/// unrevoked, unexpired and hold `scope`. A key shaped like a database key,
+/// `<id>.<secret>`, whose id is unknown is refused without trying the root
+/// key, so guessed ids cost no hashing; the root key must not contain a `.`.
+pub async fn authorize(
+    key: ApiKey,
+    scope: Scope,
+    db: &Database,
+    verifier: &KeyVerifier,
+) -> Result<(), ApiError> {
+    let Some(key) = key.into_inner() else {
+        return Err(ApiError::unauthorized("Api key is required"));
+    };
+
+    let Some((key_id, secret)) = key.split_once('.') else {
+        return if verifier.verify_root_key(&key, &db.hasher).await? {
+            Ok(())
+        } else {
+            Err(ApiError::unauthorized("Api key is invalid"))
+        };
+    };
+    let Some(record) = db.get_api_key(key_id.to_string()).await? else {
+        return Err(ApiError::unauthorized("Api key is invalid"));
+    };
+
+    // Keys issued before keyed hashing hold an Argon2 hash of their secret;
+    // they are upgraded to a digest the first time they verify.
+    let legacy_hash = record.key_hash.starts_with("$argon2");
+    let valid = if legacy_hash {
+        db.hasher
+            .verify(secret.to_string(), record.key_hash.clone())
+            .await?
+    } else {
+        verifier.matches(&key, &record.key_hash)
+    };
+    if !valid {
+        return Err(ApiError::unauthorized("Api key is invalid"));
+    }
+    if record.revoked_at.is_some() {
+        return Err(ApiError::unauthorized("Api key has been revoked"));
+    }
+    if record
+        .expires_at
+        .as_ref()
+        .is_some_and(|expires_at| **expires_at <= *Datetime::default())
+    {
+        return Err(ApiError::unauthorized("Api key has expired"));
+    }
+    if !record.scopes.contains(&scope) {
+        return Err(ApiError::forbidden(&format!(
+            "Api key is missing the {scope} scope"
+        )));
+    }
+
+    let upgraded_hash = legacy_hash.then(|| verifier.digest(&key));
+    db.touch_api_key(record.key_id, upgraded_hash).await?;
+    Ok(())
+}
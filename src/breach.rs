@@ -0,0 +1,376 @@
+//! Offline breached-password checks against a Have I Been Pwned SHA-1 corpus.
+//!
+//! The corpus can be used as downloaded, either one file of `HASH:COUNT`
+//! lines sorted by hash or a directory of range files named by their
+//! five-character prefix and holding `SUFFIX:COUNT` lines. For a smaller,
+//! faster check, `rustauth build-breach-filter` compiles either layout into
+//! a bloom filter, which trades a configurable false-positive rate for a
+//! file a fraction of the size that is held in memory.
+
+use crate::hash::to_hex;
+use rocket::tokio::task;
+use sha1::{Digest, Sha1};
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const FILTER_MAGIC: &[u8; 8] = b"RABLOOM1";
+const RANGE_PREFIX_LEN: usize = 5;
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.001;
+
+pub enum BreachList {
+    /// A sorted `HASH:COUNT` file, binary searched on disk per lookup.
+    Sorted(PathBuf),
+    /// A directory of `<prefix>` or `<prefix>.txt` range files.
+    Ranges(PathBuf),
+    Filter(BloomFilter),
+}
+
+impl BreachList {
+    /// Uses the bloom filter when one is configured, else the raw corpus.
+    pub fn open(corpus: Option<&str>, filter: Option<&str>) -> Result<Option<Self>, String> {
+        let invalid =
+            |path: &str, err: io::Error| format!("Invalid breached password list {path}: {err}");
+        if let Some(path) = filter {
+            return BloomFilter::load(Path::new(path))
+                .map(|filter| Some(BreachList::Filter(filter)))
+                .map_err(|err| invalid(path, err));
+        }
+        let Some(path) = corpus else {
+            return Ok(None);
+        };
+        let metadata = fs::metadata(path).map_err(|err| invalid(path, err))?;
+        Ok(Some(if metadata.is_dir() {
+            BreachList::Ranges(PathBuf::from(path))
+        } else {
+            BreachList::Sorted(PathBuf::from(path))
+        }))
+    }
+
+    /// Whether `password` is in the corpus. Lookups that fail to read the
+    /// corpus count as not breached, so a missing file never blocks signups.
+    /// Corpus files are searched on the blocking pool; the bloom filter is
+    /// in memory and checked in place.
+    pub async fn contains(self: &Arc<Self>, password: &str) -> bool {
+        let digest: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+        if let BreachList::Filter(filter) = self.as_ref() {
+            return filter.contains(&digest);
+        }
+        let list = Arc::clone(self);
+        task::spawn_blocking(move || list.search(&digest))
+            .await
+            .unwrap_or(false)
+    }
+
+    fn search(&self, digest: &[u8; 20]) -> bool {
+        let hash = to_hex(digest).to_ascii_uppercase();
+        match self {
+            BreachList::Sorted(path) => search_sorted(path, &hash).unwrap_or(false),
+            BreachList::Ranges(dir) => search_range(dir, &hash).unwrap_or(false),
+            BreachList::Filter(filter) => filter.contains(digest),
+        }
+    }
+}
+
+fn hash_of(line: &str) -> &str {
+    line.split(':').next().unwrap_or_default().trim()
+}
+
+/// Reads the first whole line starting at or after `offset` into `line`,
+/// returning the offset just past it, or `None` at the end of the file.
+fn read_line_at(
+    reader: &mut BufReader<File>,
+    offset: u64,
+    line: &mut String,
+) -> io::Result<Option<u64>> {
+    line.clear();
+    if offset > 0 {
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        reader.read_line(line)?;
+        line.clear();
+    } else {
+        reader.rewind()?;
+    }
+    if reader.read_line(line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(reader.stream_position()?))
+}
+
+/// Binary searches a file of lines sorted by hash, probing by byte offset.
+fn search_sorted(path: &Path, hash: &str) -> io::Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    let (mut low, mut high) = (0, reader.get_ref().metadata()?.len());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let Some(line_end) = read_line_at(&mut reader, middle, &mut line)? else {
+            high = middle;
+            continue;
+        };
+        match hash_of(&line).to_ascii_uppercase().as_str().cmp(hash) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = line_end,
+            Ordering::Greater => high = middle,
+        }
+    }
+    Ok(read_line_at(&mut reader, low, &mut line)?.is_some()
+        && hash_of(&line).eq_ignore_ascii_case(hash))
+}
+
+fn range_file(dir: &Path, prefix: &str) -> PathBuf {
+    let plain = dir.join(prefix);
+    if plain.exists() {
+        plain
+    } else {
+        dir.join(format!("{prefix}.txt"))
+    }
+}
+
+fn search_range(dir: &Path, hash: &str) -> io::Result<bool> {
+    let (prefix, suffix) = hash.split_at(RANGE_PREFIX_LEN);
+    let reader = BufReader::new(File::open(range_file(dir, prefix))?);
+    for line in reader.lines() {
+        if hash_of(&line?).eq_ignore_ascii_case(suffix) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Calls `visit` with the full hash of every entry in a corpus of either
+/// layout. Lines that are not 40 hex characters are skipped.
+fn for_each_hash(path: &Path, mut visit: impl FnMut([u8; 20])) -> io::Result<()> {
+    let mut visit_file = |file: &Path, prefix: &str| -> io::Result<()> {
+        for line in BufReader::new(File::open(file)?).lines() {
+            let line = line?;
+            let hex = format!("{prefix}{}", hash_of(&line));
+            if let Some(digest) = parse_digest(&hex) {
+                visit(digest);
+            }
+        }
+        Ok(())
+    };
+
+    if !path.is_dir() {
+        return visit_file(path, "");
+    }
+    let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let prefix = name.trim_end_matches(".txt");
+        if prefix.len() == RANGE_PREFIX_LEN && prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            visit_file(&entry.path(), prefix)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut digest = [0; 20];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// A bloom filter keyed directly by SHA-1 digests, which are already
+/// uniformly distributed, so probe positions come from double hashing the
+/// digest's first sixteen bytes.
+pub struct BloomFilter {
+    words: Vec<u64>,
+    bit_count: u64,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    fn with_capacity(entries: u64, false_positive_rate: f64) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let entries_f = entries.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-entries_f * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let bit_count = (bits as u64).max(64);
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            clippy::cast_precision_loss
+        )]
+        let hash_count = ((bit_count as f64 / entries_f) * ln2)
+            .round()
+            .clamp(1.0, 30.0) as u32;
+        BloomFilter {
+            words: vec![0; usize::try_from(bit_count.div_ceil(64)).unwrap_or(usize::MAX)],
+            bit_count,
+            hash_count,
+        }
+    }
+
+    fn positions(&self, digest: &[u8; 20]) -> impl Iterator<Item = u64> {
+        let mut first = [0; 8];
+        let mut second = [0; 8];
+        first.copy_from_slice(&digest[..8]);
+        second.copy_from_slice(&digest[8..16]);
+        let (first, second) = (u64::from_le_bytes(first), u64::from_le_bytes(second) | 1);
+        let bit_count = self.bit_count;
+        (0..u64::from(self.hash_count))
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % bit_count)
+    }
+
+    fn insert(&mut self, digest: &[u8; 20]) {
+        for position in self.positions(digest).collect::<Vec<_>>() {
+            #[allow(clippy::cast_possible_truncation)]
+            let word = (position / 64) as usize;
+            self.words[word] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, digest: &[u8; 20]) -> bool {
+        self.positions(digest).all(|position| {
+            #[allow(clippy::cast_possible_truncation)]
+            let word = (position / 64) as usize;
+            self.words[word] & (1 << (position % 64)) != 0
+        })
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; 20];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != FILTER_MAGIC {
+            return Err(invalid("not a breached password filter"));
+        }
+        let hash_count = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let bit_count = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        if hash_count == 0 || bit_count == 0 {
+            return Err(invalid("empty breached password filter"));
+        }
+
+        let word_count = usize::try_from(bit_count.div_ceil(64))
+            .map_err(|_| invalid("breached password filter is too large"))?;
+        let mut words = Vec::with_capacity(word_count);
+        let mut word = [0; 8];
+        for _ in 0..word_count {
+            reader.read_exact(&mut word)?;
+            words.push(u64::from_le_bytes(word));
+        }
+        Ok(BloomFilter {
+            words,
+            bit_count,
+            hash_count,
+        })
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(FILTER_MAGIC)?;
+        writer.write_all(&self.hash_count.to_le_bytes())?;
+        writer.write_all(&self.bit_count.to_le_bytes())?;
+        for word in &self.words {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+}
+
+/// Builds a bloom filter from a corpus in either layout, reading it twice:
+/// once to size the filter and once to fill it. Returns the entry count.
+pub fn build_filter(corpus: &Path, output: &Path, false_positive_rate: f64) -> io::Result<u64> {
+    if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "false positive rate must be between 0 and 1",
+        ));
+    }
+    let mut entries = 0;
+    for_each_hash(corpus, |_| entries += 1)?;
+
+    let mut filter = BloomFilter::with_capacity(entries, false_positive_rate);
+    for_each_hash(corpus, |digest| filter.insert(&digest))?;
+    filter.save(output)?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const BREACHED: [&str; 3] = ["password", "123456", "letmein"];
+
+    fn upper_hash(password: &str) -> String {
+        to_hex(&Sha1::digest(password.as_bytes())).to_ascii_uppercase()
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustauth-breach-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_sorted(dir: &Path) -> PathBuf {
+        let mut lines: Vec<String> = BREACHED
+            .iter()
+            .map(|password| format!("{}:{}", upper_hash(password), password.len()))
+            .collect();
+        lines.sort();
+        let path = dir.join("corpus.txt");
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        path
+    }
+
+    async fn assert_breached(list: BreachList) {
+        let list = Arc::new(list);
+        for password in BREACHED {
+            assert!(list.contains(password).await, "{password} not found");
+        }
+        assert!(!list.contains("correct horse battery staple").await);
+    }
+
+    #[rocket::async_test]
+    async fn searches_sorted_corpus() {
+        let dir = scratch_dir("sorted");
+        let path = write_sorted(&dir);
+        assert_breached(BreachList::Sorted(path)).await;
+    }
+
+    #[rocket::async_test]
+    async fn searches_range_files() {
+        let dir = scratch_dir("ranges");
+        for password in BREACHED {
+            let hash = upper_hash(password);
+            let (prefix, suffix) = hash.split_at(RANGE_PREFIX_LEN);
+            fs::write(dir.join(format!("{prefix}.txt")), format!("{suffix}:1\n")).unwrap();
+        }
+        assert_breached(BreachList::Ranges(dir)).await;
+    }
+
+    #[rocket::async_test]
+    async fn builds_and_loads_bloom_filter() {
+        let dir = scratch_dir("filter");
+        let corpus = write_sorted(&dir);
+        let output = dir.join("corpus.bloom");
+        assert_eq!(build_filter(&corpus, &output, 0.001).unwrap(), 3);
+        let list = BreachList::open(None, Some(output.to_str().unwrap()))
+            .unwrap()
+            .unwrap();
+        assert_breached(list).await;
+    }
+
+    #[test]
+    fn unreadable_corpus_counts_as_not_breached() {
+        let list = BreachList::Sorted(PathBuf::from("/nonexistent/corpus.txt"));
+        let digest: [u8; 20] = Sha1::digest(b"password").into();
+        assert!(!list.search(&digest));
+    }
+}
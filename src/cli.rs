@@ -0,0 +1,130 @@
+//! Maintenance commands, run as `rustauth <command> [args]` instead of
+//! starting the server.
+
+use crate::{
+    breach::{build_filter, DEFAULT_FALSE_POSITIVE_RATE},
+    database::Database,
+    settings::{check_json, read_settings},
+    templates::{EmailTemplates, SAMPLE_VARIABLES},
+};
+use rocket::tokio::runtime::Handle;
+use std::{fs, path::Path};
+
+const USAGE: &str = "Usage:
+  rustauth                       start the server
+  rustauth build-breach-filter <corpus> <output> [false-positive-rate]
+  rustauth preview-email <template> [locale]
+  rustauth export-user-data <username> [output]";
+
+/// Runs the command named in `args`, returning the exit code, or `None`
+/// when no command was given and the server should start.
+pub fn run(args: &[String]) -> Option<i32> {
+    let (command, args) = args.split_first()?;
+    let code = match command.as_str() {
+        "build-breach-filter" => build_breach_filter(args),
+        "preview-email" => preview_email(args),
+        "export-user-data" => export_user_data(args),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            0
+        }
+        _ => {
+            eprintln!("Unknown command {command:?}\n{USAGE}");
+            2
+        }
+    };
+    Some(code)
+}
+
+fn build_breach_filter(args: &[String]) -> i32 {
+    let ([corpus, output] | [corpus, output, _]) = args else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+    let false_positive_rate = match args.get(2).map(|rate| rate.parse::<f64>()) {
+        Some(Ok(rate)) => rate,
+        Some(Err(err)) => {
+            eprintln!("Invalid false positive rate: {err}");
+            return 2;
+        }
+        None => DEFAULT_FALSE_POSITIVE_RATE,
+    };
+
+    match build_filter(Path::new(corpus), Path::new(output), false_positive_rate) {
+        Ok(entries) => {
+            println!("Wrote {output} with {entries} breached password hashes");
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to build breached password filter: {err}");
+            1
+        }
+    }
+}
+
+/// Renders an email template with sample data, using the template
+/// directory and default locale from `settings.json`.
+fn preview_email(args: &[String]) -> i32 {
+    let ([name] | [name, _]) = args else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+    let rendered = EmailTemplates::from_settings(&read_settings()).and_then(|templates| {
+        templates.render(name, args.get(1).map(String::as_str), &SAMPLE_VARIABLES)
+    });
+    match rendered {
+        Ok(email) => {
+            println!("Subject: {}\n\n{}", email.subject, email.text);
+            if let Some(html) = email.html {
+                println!("--- HTML ---\n{html}");
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            1
+        }
+    }
+}
+
+/// Writes the data export for one user as JSON to `output`, or stdout.
+/// Connects to the database like the server does, so a local database
+/// must not be in use by a running server.
+fn export_user_data(args: &[String]) -> i32 {
+    let ([username] | [username, _]) = args else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+    let (settings, password) = check_json();
+    let export = Handle::current().block_on(async {
+        let db = Database::new(settings, password).await?;
+        db.user_data(username.clone()).await
+    });
+    let export = match export {
+        Ok(Some(export)) => export,
+        Ok(None) => {
+            eprintln!("User {username} not found");
+            return 1;
+        }
+        Err(err) => {
+            eprintln!("Failed to export user data: {err}");
+            return 1;
+        }
+    };
+
+    let json = serde_json::to_string_pretty(&export).expect("export serializes to JSON");
+    let Some(output) = args.get(1) else {
+        println!("{json}");
+        return 0;
+    };
+    match fs::write(output, json) {
+        Ok(()) => {
+            println!("Wrote {output}");
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to write {output}: {err}");
+            1
+        }
+    }
+}
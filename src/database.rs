@@ -1,17 +1,36 @@
 use crate::{
-    hash::{generate_salt, hash_password, verify_password},
-    models::{EmailLogin, LoginSuccess, SignUp, User, UsernameLogin},
+    account::AccountStatus,
+    api_key::Scope,
+    email_queue::{EmailStatus, CLAIM_SECONDS},
+    error::AuthError,
+    export::{ExportFilter, EXPORT_BATCH_SIZE},
+    hash::{is_supported_hash, HashError, Hasher},
+    import::{ParsedRow, IMPORT_BATCH_SIZE, IMPORT_HASH_CONCURRENCY},
+    models::{
+        ApiKeyRecord, EmailLogin, ImportRow, NewApiKey, QueuedEmail, SignUp, User, UserUpdate,
+        UsernameLogin,
+    },
+    policy::PasswordPolicy,
+    responses::{ImportReport, ImportRowError, LoginSuccess, UserDataExport},
     settings::{DatabaseType, Settings},
+    templates::{is_valid_locale, RenderedEmail},
 };
-use rand::Rng;
+use rocket::{
+    futures::{stream, StreamExt},
+    tokio::time::{sleep, Duration},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc};
 use surrealdb::{
     engine::local::{Db, RocksDb},
     engine::remote::ws::{Client, Ws},
     error::Db::Thrown,
     opt::{auth::Root, Config},
+    sql::Datetime,
     {Error, Response, Surreal},
 };
 
+#[derive(Clone)]
 pub enum DbClient {
     Db(Surreal<Db>),
     Client(Surreal<Client>),
@@ -24,17 +43,135 @@ impl DbClient {
             DbClient::Client(client) => client.query(query).await,
         }
     }
+
+    async fn query_bind(
+        &self,
+        query: String,
+        bindings: impl Serialize + Send + 'static,
+    ) -> Result<Response, Error> {
+        match self {
+            DbClient::Db(db) => db.query(query).bind(bindings).await,
+            DbClient::Client(client) => client.query(query).bind(bindings).await,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NewUserRecord {
+    email: String,
+    username: String,
+    password: String,
+    logged_in: bool,
+    verified: bool,
+    status: AccountStatus,
 }
 
+/// Bindings for a new key. `expires_at` is a `Datetime` so it is stored as
+/// a datetime rather than a string.
+#[derive(Serialize)]
+struct NewApiKeyRecord {
+    key_id: String,
+    name: String,
+    owner: String,
+    key_hash: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<Datetime>,
+}
+
+/// Bindings for a queued email. `expires_at` is when the code it carries
+/// stops working, after which the message is no longer worth sending.
+#[derive(Serialize)]
+struct NewQueuedEmail {
+    recipient: String,
+    subject: String,
+    text: String,
+    html: Option<String>,
+    expires_at: Option<Datetime>,
+}
+
+#[derive(Deserialize)]
+struct ExistingIdentity {
+    email: String,
+    username: String,
+}
+
+/// How many recent passwords, counting the current one, a user may not reuse.
+const DEFAULT_PASSWORD_HISTORY_SIZE: usize = 5;
+const DEFAULT_RECOVERY_CODE_TTL_MINUTES: u32 = 15;
+const DEFAULT_RECOVERY_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_RECOVERY_RESEND_SECONDS: u64 = 60;
+const DEFAULT_EMAIL_VERIFICATION_TTL_MINUTES: u32 = 24 * 60;
+const DEFAULT_EMAIL_VERIFICATION_RESEND_SECONDS: u64 = 60;
+/// Consecutive failed logins that lock an account, and for how long.
+const DEFAULT_LOGIN_LOCKOUT_THRESHOLD: u32 = 10;
+const DEFAULT_LOGIN_LOCKOUT_MINUTES: u32 = 15;
+/// How long a deleted account can be restored before it is purged.
+const DEFAULT_DELETION_GRACE_DAYS: u32 = 30;
+/// How long a username stays reserved for its previous owner after a rename.
+const DEFAULT_USERNAME_RESERVATION_DAYS: u32 = 30;
+/// How long an import waits before retrying a hash the pool turned away.
+const IMPORT_BUSY_RETRY: Duration = Duration::from_millis(200);
+const USERNAME_RESERVED: &str = "Username was recently used by another account";
+const EMAIL_CHANGE_REQUIRED: &str =
+    "Email can only be changed with POST /v1/users/<id>/email, which confirms the new address";
+
+#[derive(Clone)]
 pub struct Database {
     pub client: DbClient,
-    pub name_space: String,
-    pub db_name: String,
+    pub hasher: Arc<Hasher>,
+    pub password_policy: Arc<PasswordPolicy>,
+    pub password_history_size: usize,
+    pub recovery_code_ttl_minutes: u32,
+    pub recovery_max_attempts: u32,
+    pub recovery_resend_seconds: u64,
+    pub email_verification_ttl_minutes: u32,
+    pub email_verification_resend_seconds: u64,
+    pub require_verified_email: bool,
+    pub username_reservation_days: u32,
+    pub deletion_grace_days: u32,
+    pub login_lockout_threshold: u32,
+    pub login_lockout_minutes: u32,
 }
 
 impl Database {
     pub async fn new(db_settings: Settings, root_password: String) -> Result<Self, Error> {
-        match db_settings.clone().database_type.unwrap() {
+        let hasher = Hasher::from_settings(&db_settings).map_err(|err| Error::Db(Thrown(err)))?;
+        let hasher = Arc::new(hasher);
+        let password_policy =
+            PasswordPolicy::from_settings(&db_settings).map_err(|err| Error::Db(Thrown(err)))?;
+        let password_policy = Arc::new(password_policy);
+        let password_history_size = db_settings
+            .password_history_size
+            .unwrap_or(DEFAULT_PASSWORD_HISTORY_SIZE);
+        let recovery_code_ttl_minutes = db_settings
+            .recovery_code_ttl_minutes
+            .unwrap_or(DEFAULT_RECOVERY_CODE_TTL_MINUTES);
+        let recovery_max_attempts = db_settings
+            .recovery_max_attempts
+            .unwrap_or(DEFAULT_RECOVERY_MAX_ATTEMPTS);
+        let recovery_resend_seconds = db_settings
+            .recovery_resend_seconds
+            .unwrap_or(DEFAULT_RECOVERY_RESEND_SECONDS);
+        let email_verification_ttl_minutes = db_settings
+            .email_verification_ttl_minutes
+            .unwrap_or(DEFAULT_EMAIL_VERIFICATION_TTL_MINUTES);
+        let email_verification_resend_seconds = db_settings
+            .email_verification_resend_seconds
+            .unwrap_or(DEFAULT_EMAIL_VERIFICATION_RESEND_SECONDS);
+        let require_verified_email = db_settings.require_verified_email.unwrap_or(false);
+        let username_reservation_days = db_settings
+            .username_reservation_days
+            .unwrap_or(DEFAULT_USERNAME_RESERVATION_DAYS);
+        let deletion_grace_days = db_settings
+            .deletion_grace_days
+            .unwrap_or(DEFAULT_DELETION_GRACE_DAYS);
+        let login_lockout_threshold = db_settings
+            .login_lockout_threshold
+            .unwrap_or(DEFAULT_LOGIN_LOCKOUT_THRESHOLD);
+        let login_lockout_minutes = db_settings
+            .login_lockout_minutes
+            .unwrap_or(DEFAULT_LOGIN_LOCKOUT_MINUTES);
+        let database = match db_settings.clone().database_type.unwrap() {
             DatabaseType::Local => {
                 let config = Config::default().strict().user(Root {
                     username: db_settings.root_user.clone().unwrap().as_str(),
@@ -50,11 +187,22 @@ impl Database {
                     })
                     .await?;
                 client.use_ns("my_ns").use_db("my_db").await?;
-                Ok(Database {
+                Database {
                     client: DbClient::Db(client),
-                    name_space: String::from("my_ns"),
-                    db_name: String::from("my_db"),
-                })
+                    hasher,
+                    password_policy,
+                    password_history_size,
+                    recovery_code_ttl_minutes,
+                    recovery_max_attempts,
+                    recovery_resend_seconds,
+                    email_verification_ttl_minutes,
+                    email_verification_resend_seconds,
+                    require_verified_email,
+                    username_reservation_days,
+                    deletion_grace_days,
+                    login_lockout_threshold,
+                    login_lockout_minutes,
+                }
             }
             DatabaseType::Remote => {
                 let client = Surreal::new::<Ws>(db_settings.database_endpoint.unwrap()).await?;
@@ -65,18 +213,104 @@ impl Database {
                     })
                     .await?;
                 client.use_ns("my_ns").use_db("my_db").await.unwrap();
-                Ok(Database {
+                Database {
                     client: DbClient::Client(client),
-                    name_space: String::from("my_ns"),
-                    db_name: String::from("my_db"),
-                })
+                    hasher,
+                    password_policy,
+                    password_history_size,
+                    recovery_code_ttl_minutes,
+                    recovery_max_attempts,
+                    recovery_resend_seconds,
+                    email_verification_ttl_minutes,
+                    email_verification_resend_seconds,
+                    require_verified_email,
+                    username_reservation_days,
+                    deletion_grace_days,
+                    login_lockout_threshold,
+                    login_lockout_minutes,
+                }
             }
+        };
+        database.backfill_account_statuses().await?;
+        Ok(database)
+    }
+
+    /// Gives accounts created before statuses existed the status their
+    /// other fields imply. Accounts from before email verification existed
+    /// were already in use, so like imported ones they count as verified.
+    async fn backfill_account_statuses(&self) -> Result<(), Error> {
+        let query = "UPDATE Users SET verified = true WHERE verified = NONE; UPDATE Users SET status = 'deleted', status_changed_at = time::now() WHERE status = NONE AND deleted_at != NONE; UPDATE Users SET status = 'active', status_changed_at = time::now() WHERE status = NONE AND verified = true; UPDATE Users SET status = 'pending', status_changed_at = time::now() WHERE status = NONE"
+            .to_string();
+        self.client.query(query).await?.check()?;
+        Ok(())
+    }
+
+    /// Moves `user` to status `to`, recording the reason and time in their
+    /// status history. `extra` holds further assignments, starting with a
+    /// comma, to make in the same update. Returns `None` if the user's
+    /// status changed concurrently.
+    async fn transition(
+        &self,
+        user: &User,
+        to: AccountStatus,
+        reason: &str,
+        extra: &str,
+    ) -> Result<Option<User>, Error> {
+        if !user.status.can_become(to) {
+            return Err(Error::Db(Thrown(format!(
+                "Cannot change account status from {} to {}",
+                user.status.as_str(),
+                to.as_str()
+            ))));
         }
+        let mut result = self
+            .client
+            .query_bind(
+                format!(
+                    "UPDATE Users SET status = $to, status_reason = $reason, status_changed_at = time::now(), status_history += {{ from: $from, to: $to, reason: $reason, at: time::now() }}{extra} WHERE email = $email AND status = $from"
+                ),
+                serde_json::json!({
+                    "from": user.status,
+                    "to": to,
+                    "reason": reason,
+                    "email": user.email,
+                }),
+            )
+            .await?;
+        result.take(0)
+    }
+
+    /// Applies an admin status change. Suspending signs the user out;
+    /// leaving `locked`, for any status, clears the failed login count and
+    /// the lock expiry.
+    pub async fn set_status(
+        &self,
+        user: &User,
+        to: AccountStatus,
+        reason: &str,
+    ) -> Result<Option<User>, Error> {
+        let sign_out = if to == AccountStatus::Suspended {
+            ", logged_in = false"
+        } else {
+            ""
+        };
+        let unlock = if user.status == AccountStatus::Locked {
+            ", failed_logins = 0, locked_until = NONE"
+        } else {
+            ""
+        };
+        self.transition(user, to, reason, &format!("{sign_out}{unlock}"))
+            .await
     }
 
     pub async fn check_duplicate_email(&self, email: String) -> Result<bool, Error> {
-        let query = format!("SELECT * FROM Users WHERE email = '{email}'");
-        let result = self.client.query(query).await;
+        let result = self
+            .client
+            .query_bind(
+                "SELECT * FROM Users WHERE email = $email".to_string(),
+                serde_json::json!({ "email": email }),
+            )
+            .await;
 
         match result {
             Ok(mut result_set) => {
@@ -88,8 +322,13 @@ impl Database {
     }
 
     pub async fn check_duplicate_username(&self, username: String) -> Result<bool, Error> {
-        let query = format!("SELECT * FROM Users WHERE username = '{username}'");
-        let result = self.client.query(query).await;
+        let result = self
+            .client
+            .query_bind(
+                "SELECT * FROM Users WHERE username = $username".to_string(),
+                serde_json::json!({ "username": username }),
+            )
+            .await;
         match result {
             Ok(mut result_set) => {
                 let created: Option<User> = result_set.take(0)?;
@@ -99,162 +338,489 @@ impl Database {
         }
     }
 
-    pub async fn signup(&self, user: SignUp) -> Result<Option<User>, Error> {
+    /// Whether another account gave up `username` within the last
+    /// `username_reservation_days`. `owner_email` may reclaim its own names.
+    pub async fn is_username_reserved(
+        &self,
+        username: &str,
+        owner_email: Option<&str>,
+    ) -> Result<bool, Error> {
+        if self.username_reservation_days == 0 {
+            return Ok(false);
+        }
+        let mut result = self
+            .client
+            .query_bind(
+                format!(
+                    "SELECT * FROM Users WHERE previous_usernames != NONE AND previous_usernames[WHERE username = $username AND changed_at > time::now() - {}d] != []",
+                    self.username_reservation_days
+                ),
+                serde_json::json!({ "username": username }),
+            )
+            .await?;
+        let holders: Vec<User> = result.take(0)?;
+        Ok(holders
+            .iter()
+            .any(|holder| Some(holder.email.as_str()) != owner_email))
+    }
+
+    pub async fn signup(&self, user: SignUp) -> Result<Option<User>, AuthError> {
         let is_duplicate_email = self.check_duplicate_email(user.email.clone()).await;
         match is_duplicate_email {
             Ok(duplicate) => {
                 if duplicate {
-                    return Err(Error::Db(Thrown("Email already in use".to_string())));
+                    return Err(Error::Db(Thrown("Email already in use".to_string())).into());
                 }
             }
-            Err(err) => return Err(err),
-        };
+            Err(err) => return Err(err.into()),
+        }
 
         let is_duplicate_username = self.check_duplicate_username(user.username.clone()).await;
         match is_duplicate_username {
             Ok(duplicate) => {
                 if duplicate {
-                    return Err(Error::Db(Thrown("Username already taken".to_string())));
+                    return Err(Error::Db(Thrown("Username already taken".to_string())).into());
                 }
             }
-            Err(err) => return Err(err),
+            Err(err) => return Err(err.into()),
+        }
+
+        if self.is_username_reserved(&user.username, None).await? {
+            return Err(Error::Db(Thrown(USERNAME_RESERVED.to_string())).into());
+        }
+
+        let locale = match &user.locale {
+            Some(locale) if is_valid_locale(locale) => format!("'{locale}'"),
+            Some(_) => return Err(Error::Db(Thrown("Invalid locale".to_string())).into()),
+            None => "NONE".to_string(),
         };
 
-        let salt = generate_salt();
-        let password_hash = hash_password(user.password.clone(), salt.clone()).ok();
+        let password_hash = self.hasher.hash(user.password.clone()).await?;
 
         let query = format!(
-            "CREATE Users SET email='{}', username='{}', password='{}', logged_in=false",
-            user.email,
-            user.username,
-            password_hash.unwrap()
+            "CREATE Users SET email='{}', username='{}', password='{password_hash}', logged_in=false, verified=false, created_at=time::now(), locale={locale}, status='pending', status_changed_at=time::now(), status_history=[{{ from: NONE, to: 'pending', reason: 'Signed up', at: time::now() }}]",
+            user.email, user.username
         );
         let mut result = self.client.query(query).await?;
-        result.take(0)
+        Ok(result.take(0)?)
     }
 
-    pub async fn email_login(&self, credentials: EmailLogin) -> Result<LoginSuccess, Error> {
-        let query = format!(
-            "SELECT * FROM Users WHERE email='{}'",
-            credentials.email.clone()
-        );
-        let result = self.client.query(query).await;
-        match result {
-            Ok(mut result_set) => {
-                let user: Option<User> = result_set.take(0)?;
-                match user {
-                    Some(user) => {
-                        let verify_password =
-                            verify_password(credentials.password.clone(), user.password.clone())
-                                .ok()
-                                .unwrap();
-                        if verify_password {
-                            if user.logged_in {
-                                Err(Error::Db(Thrown("User already logged in".to_string())))
-                            } else {
-                                let logged_in_query = format!(
-                                    "UPDATE Users SET logged_in = true WHERE email='{}'",
-                                    user.email.clone()
-                                );
-                                match self.client.query(logged_in_query).await {
-                                    Ok(_) => Ok(LoginSuccess {
-                                        email: user.email.clone(),
-                                        username: user.username.clone(),
-                                    }),
-                                    Err(err) => Err(err),
-                                }
-                            }
-                        } else {
-                            Err(Error::Db(Thrown(
-                                "Email or Password is incorret try again".to_string(),
-                            )))
-                        }
-                    }
-                    None => Err(Error::Db(Thrown(
-                        "Email or Password is incorret try again".to_string(),
-                    ))),
-                }
+    pub async fn email_login(&self, credentials: EmailLogin) -> Result<LoginSuccess, AuthError> {
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT * FROM Users WHERE email = $email".to_string(),
+                serde_json::json!({ "email": credentials.email }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        self.login(
+            user,
+            credentials.password,
+            "Email or Password is incorret try again",
+        )
+        .await
+    }
+
+    pub async fn username_login(
+        &self,
+        credentials: UsernameLogin,
+    ) -> Result<LoginSuccess, AuthError> {
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT * FROM Users WHERE username = $username".to_string(),
+                serde_json::json!({ "username": credentials.username }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        self.login(
+            user,
+            credentials.password,
+            "Username or Password is incorret try again",
+        )
+        .await
+    }
+
+    /// Signs `user` in if `password` matches and their status allows it.
+    /// `failure` is the error for unknown users and wrong passwords.
+    async fn login(
+        &self,
+        user: Option<User>,
+        password: String,
+        failure: &str,
+    ) -> Result<LoginSuccess, AuthError> {
+        let invalid = || AuthError::Db(Error::Db(Thrown(failure.to_string())));
+        let Some(user) = user else {
+            return Err(invalid());
+        };
+        let Some(user) = self.check_password(user, &password).await? else {
+            return Err(invalid());
+        };
+        self.rehash_if_needed(&user.email, &password, &user.password)
+            .await;
+
+        let refusal = match user.status {
+            AccountStatus::Suspended => Some(AuthError::Suspended),
+            AccountStatus::Deleted => Some(AuthError::PendingDeletion),
+            AccountStatus::Pending if self.require_verified_email => Some(AuthError::Unverified),
+            _ => None,
+        };
+        if let Some(refusal) = refusal {
+            return Err(refusal);
+        }
+        if user.logged_in {
+            return Err(Error::Db(Thrown("User already logged in".to_string())).into());
+        }
+
+        self.client
+            .query_bind(
+                "UPDATE Users SET logged_in = true, failed_logins = 0 WHERE email = $email"
+                    .to_string(),
+                serde_json::json!({ "email": user.email }),
+            )
+            .await?;
+        Ok(LoginSuccess::from(user))
+    }
+
+    /// Returns `user` if `password` is theirs. A locked account is refused
+    /// before the password is checked, until its lock expires, and a wrong
+    /// password counts towards locking the account.
+    pub async fn check_password(
+        &self,
+        mut user: User,
+        password: &str,
+    ) -> Result<Option<User>, AuthError> {
+        if user.status == AccountStatus::Locked {
+            let locked = user
+                .locked_until
+                .as_ref()
+                .is_none_or(|until| **until > *Datetime::default());
+            if locked {
+                return Err(AuthError::Locked);
+            }
+            let to = AccountStatus::usable(user.verified);
+            match self.set_status(&user, to, "Lock expired").await? {
+                Some(unlocked) => user = unlocked,
+                None => return Ok(None),
             }
-            Err(err) => Err(err),
+        }
+
+        if self
+            .hasher
+            .verify(password.to_string(), user.password.clone())
+            .await?
+        {
+            Ok(Some(user))
+        } else {
+            self.record_failed_login(&user).await?;
+            Ok(None)
         }
     }
 
-    pub async fn username_login(&self, credentials: UsernameLogin) -> Result<LoginSuccess, Error> {
-        let query = format!(
-            "SELECT * FROM Users WHERE username='{}'",
-            credentials.username.clone()
-        );
-        let result = self.client.query(query).await;
-        match result {
-            Ok(mut result_) => {
-                let user: Option<User> = result_.take(0)?;
-                match user {
-                    Some(user_) => {
-                        let verify_password =
-                            verify_password(credentials.password.clone(), user_.password.clone())
-                                .ok()
-                                .unwrap();
-                        if verify_password {
-                            if user_.logged_in {
-                                Err(Error::Db(Thrown("User already logged in".to_string())))
-                            } else {
-                                let logged_in_query = format!(
-                                    "UPDATE Users SET logged_in = true WHERE email='{}'",
-                                    user_.email.clone()
-                                );
-                                match self.client.query(logged_in_query).await {
-                                    Ok(_) => Ok(LoginSuccess {
-                                        email: user_.email.clone(),
-                                        username: user_.username.clone(),
-                                    }),
-                                    Err(err) => Err(err),
-                                }
-                            }
-                        } else {
-                            Err(Error::Db(Thrown(
-                                "Username or Password is incorret try again".to_string(),
-                            )))
-                        }
-                    }
-                    None => Err(Error::Db(Thrown(
-                        "Username or Password is incorret try again".to_string(),
-                    ))),
-                }
+    /// Counts a wrong password against the user, locking a usable account
+    /// for `login_lockout_minutes` once `login_lockout_threshold` failures
+    /// in a row are reached. A threshold of 0 disables locking.
+    async fn record_failed_login(&self, user: &User) -> Result<(), Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                "UPDATE Users SET failed_logins += 1 WHERE email = $email".to_string(),
+                serde_json::json!({ "email": user.email }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        let Some(user) = user else {
+            return Ok(());
+        };
+        let usable = matches!(user.status, AccountStatus::Pending | AccountStatus::Active);
+        if self.login_lockout_threshold > 0
+            && user.failed_logins >= self.login_lockout_threshold
+            && usable
+        {
+            let extra = format!(
+                ", locked_until = time::now() + {}m, logged_in = false",
+                self.login_lockout_minutes
+            );
+            self.transition(
+                &user,
+                AccountStatus::Locked,
+                "Too many failed logins",
+                &extra,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn rehash_if_needed(&self, email: &str, password: &str, password_hash: &str) {
+        if !self.hasher.needs_rehash(password_hash) {
+            return;
+        }
+        // Best effort: a failed upgrade leaves the old hash, which still verifies.
+        if let Ok(new_hash) = self.hasher.hash(password.to_string()).await {
+            let _ = self
+                .client
+                .query_bind(
+                    "UPDATE Users SET password = $password WHERE email = $email".to_string(),
+                    serde_json::json!({ "password": new_hash, "email": email }),
+                )
+                .await;
+        }
+    }
+
+    /// Replaces a user's password, refusing any of their last
+    /// `password_history_size` passwords. The outgoing hash is kept in
+    /// `password_history`, which never holds more than that many entries.
+    /// Callers check the password policy first.
+    pub async fn set_password(&self, user: &User, new_password: String) -> Result<(), AuthError> {
+        let recent = std::iter::once(&user.password)
+            .chain(&user.password_history)
+            .take(self.password_history_size);
+        for password_hash in recent {
+            if self
+                .hasher
+                .verify(new_password.clone(), password_hash.clone())
+                .await?
+            {
+                return Err(Error::Db(Thrown(
+                    "Password was used recently, choose a different one".to_string(),
+                ))
+                .into());
             }
-            Err(err) => Err(err),
         }
+
+        let new_hash = self.hasher.hash(new_password).await?;
+        let history: Vec<&String> = std::iter::once(&user.password)
+            .chain(&user.password_history)
+            .take(self.password_history_size.saturating_sub(1))
+            .collect();
+        self.client
+            .query_bind(
+                "UPDATE Users SET password = $password, password_history = $history WHERE email = $email"
+                    .to_string(),
+                serde_json::json!({
+                    "password": new_hash,
+                    "history": history,
+                    "email": user.email,
+                }),
+            )
+            .await?
+            .check()?;
+        Ok(())
     }
 
     pub async fn get_user(&self, username: String) -> Result<Option<User>, Error> {
-        let query = format!("SELECT * FROM Users WHERE username = '{username}'");
-        let mut result = self.client.query(query).await?;
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT * FROM Users WHERE username = $username".to_string(),
+                serde_json::json!({ "username": username }),
+            )
+            .await?;
         let created: Option<User> = result.take(0)?;
         Ok(created)
     }
 
-    pub async fn delete_user(&self, username: String) -> Result<Option<User>, Error> {
+    /// Applies a username or locale change. Changing the email is refused.
+    pub async fn update_user(
+        &self,
+        username: String,
+        update: UserUpdate,
+    ) -> Result<Option<User>, Error> {
+        let Some(user) = self.get_user(username.clone()).await? else {
+            return Err(Error::Db(Thrown("User not found".to_string())));
+        };
+        // The new address has to confirm first, so the email only changes
+        // through `start_email_change` and `confirm_email_change`.
+        if update.email.is_some_and(|email| email != user.email) {
+            return Err(Error::Db(Thrown(EMAIL_CHANGE_REQUIRED.to_string())));
+        }
+        let mut assignments = Vec::new();
+        let mut bindings = serde_json::Map::new();
+
+        if let Some(new_username) = update.username.filter(|name| *name != user.username) {
+            if self.check_duplicate_username(new_username.clone()).await? {
+                return Err(Error::Db(Thrown("Username already taken".to_string())));
+            }
+            if self
+                .is_username_reserved(&new_username, Some(&user.email))
+                .await?
+            {
+                return Err(Error::Db(Thrown(USERNAME_RESERVED.to_string())));
+            }
+            assignments.push(
+                "username = $new_username, previous_usernames += { username: $username, changed_at: time::now() }",
+            );
+            bindings.insert("new_username".to_string(), new_username.into());
+        }
+
+        if let Some(locale) = update
+            .locale
+            .filter(|locale| Some(locale) != user.locale.as_ref())
+        {
+            if !is_valid_locale(&locale) {
+                return Err(Error::Db(Thrown("Invalid locale".to_string())));
+            }
+            assignments.push("locale = $locale");
+            bindings.insert("locale".to_string(), locale.into());
+        }
+
+        if assignments.is_empty() {
+            return Ok(Some(user));
+        }
+
+        bindings.insert("username".to_string(), username.into());
+        let mut result = self
+            .client
+            .query_bind(
+                format!(
+                    "UPDATE Users SET {} WHERE username = $username",
+                    assignments.join(", ")
+                ),
+                bindings,
+            )
+            .await?;
+        result.take(0)
+    }
+
+    /// Schedules the user for deletion after `deletion_grace_days` and signs
+    /// them out. Until then the account can be restored; afterwards
+    /// `purge_deleted_users` removes it.
+    pub async fn delete_user(&self, username: String) -> Result<Option<User>, AuthError> {
         let get_user_result = self.get_user(username.clone()).await;
         match get_user_result {
-            Ok(Some(_user)) => {
-                let query = format!("DELETE Users WHERE username = '{username}'");
-                let mut result = self.client.query(query).await?;
-                let deleted_user: Option<User> = result.take(0)?;
-                Ok(deleted_user)
+            Ok(Some(user)) if user.status == AccountStatus::Deleted => {
+                Err(AuthError::PendingDeletion)
             }
-            Ok(None) => Err(Error::Db(Thrown("User not found".to_string()))),
-            Err(err) => Err(err),
+            Ok(Some(user)) => {
+                let extra = format!(
+                    ", deleted_at = time::now(), purge_after = time::now() + {}d, logged_in = false",
+                    self.deletion_grace_days
+                );
+                Ok(self
+                    .transition(&user, AccountStatus::Deleted, "Account deleted", &extra)
+                    .await?)
+            }
+            Ok(None) => Err(Error::Db(Thrown("User not found".to_string())).into()),
+            Err(err) => Err(err.into()),
         }
     }
 
+    /// The addresses mail for `user` may have gone to: their email, one
+    /// they are changing to and any they held before. Addresses another
+    /// account now holds are left out, as that mail may be theirs.
+    async fn own_addresses(&self, user: &User) -> Result<Vec<String>, Error> {
+        let mut addresses = vec![user.email.clone()];
+        addresses.extend(
+            user.email_change
+                .as_ref()
+                .map(|change| change.new_email.clone()),
+        );
+        addresses.extend(user.previous_emails.iter().cloned());
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT VALUE email FROM Users WHERE email INSIDE $addresses AND email != $email"
+                    .to_string(),
+                serde_json::json!({ "addresses": addresses, "email": user.email }),
+            )
+            .await?;
+        let taken: Vec<String> = result.take(0)?;
+        addresses.retain(|address| !taken.contains(address));
+        Ok(addresses)
+    }
+
+    /// Gathers everything stored about a user: their record, mail queued
+    /// to any of their addresses, and API keys naming them as owner.
+    pub async fn user_data(&self, username: String) -> Result<Option<UserDataExport>, Error> {
+        let Some(user) = self.get_user(username).await? else {
+            return Ok(None);
+        };
+        let addresses = self.own_addresses(&user).await?;
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT * FROM EmailQueue WHERE recipient INSIDE $addresses ORDER BY created_at; SELECT * FROM ApiKeys WHERE owner = $username OR owner INSIDE $addresses ORDER BY created_at"
+                    .to_string(),
+                serde_json::json!({
+                    "addresses": addresses,
+                    "username": user.username,
+                }),
+            )
+            .await?;
+        let emails: Vec<QueuedEmail> = result.take(0)?;
+        let api_keys: Vec<ApiKeyRecord> = result.take(1)?;
+        Ok(Some(UserDataExport::new(user, emails, api_keys)))
+    }
+
+    /// Cancels a pending deletion that has not yet lapsed, returning the
+    /// account to the status it had before.
+    pub async fn restore_user(&self, user: &User) -> Result<Option<User>, Error> {
+        let restorable = user.status == AccountStatus::Deleted
+            && user
+                .purge_after
+                .as_ref()
+                .is_some_and(|purge_after| **purge_after > *Datetime::default());
+        if !restorable {
+            return Ok(None);
+        }
+        let to = user
+            .status_history
+            .iter()
+            .rev()
+            .find(|change| change.to == AccountStatus::Deleted)
+            .and_then(|change| change.from)
+            .filter(|from| *from != AccountStatus::Deleted)
+            .unwrap_or(AccountStatus::usable(user.verified));
+        self.transition(
+            user,
+            to,
+            "Deletion cancelled",
+            ", deleted_at = NONE, purge_after = NONE",
+        )
+        .await
+    }
+
+    /// Hard-deletes accounts whose grace period has lapsed, along with any
+    /// queued mail addressed to them and the API keys they own. Returns how
+    /// many were removed.
+    pub async fn purge_deleted_users(&self) -> Result<usize, Error> {
+        let query = "SELECT * FROM Users WHERE purge_after != NONE AND purge_after <= time::now()"
+            .to_string();
+        let mut result = self.client.query(query).await?;
+        let lapsed: Vec<User> = result.take(0)?;
+        for user in &lapsed {
+            let addresses = self.own_addresses(user).await?;
+            self.client
+                .query_bind(
+                    "DELETE EmailQueue WHERE recipient INSIDE $addresses; DELETE ApiKeys WHERE owner = $username OR owner INSIDE $addresses; DELETE Users WHERE email = $email AND purge_after <= time::now()"
+                        .to_string(),
+                    serde_json::json!({
+                        "addresses": addresses,
+                        "username": user.username,
+                        "email": user.email,
+                    }),
+                )
+                .await?
+                .check()?;
+        }
+        Ok(lapsed.len())
+    }
+
     pub async fn signout(&self, username: String) -> Result<String, Error> {
         let get_user_result = self.get_user(username.clone()).await;
         match get_user_result {
             Ok(Some(user)) => {
                 if user.logged_in {
-                    let query = format!(
-                        "UPDATE Users SET logged_in=false WHERE username = '{username}'"
-                    );
-                    let mut result = self.client.query(query).await?;
+                    let mut result = self
+                        .client
+                        .query_bind(
+                            "UPDATE Users SET logged_in = false WHERE username = $username"
+                                .to_string(),
+                            serde_json::json!({ "username": username }),
+                        )
+                        .await?;
                     let _deleted_user: Option<User> = result.take(0)?;
                     Ok("User successfully logged out".to_string())
                 } else {
@@ -266,27 +832,626 @@ impl Database {
         }
     }
 
-    async fn add_code(&self, username: String, code: i32) -> Result<User, Error> {
-        let query = format!("UPDATE Users SET recovery_code={code} WHERE username='{username}'");
+    pub async fn get_user_by_email(&self, email: String) -> Result<Option<User>, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT * FROM Users WHERE email = $email".to_string(),
+                serde_json::json!({ "email": email }),
+            )
+            .await?;
+        result.take(0)
+    }
+
+    /// Stores the digest of a new recovery code, replacing any earlier one,
+    /// and returns when it expires. Returns `None` without issuing a code if
+    /// one was sent less than `recovery_resend_seconds` ago, so requesting
+    /// new codes cannot reset the attempt count faster than that.
+    pub async fn start_recovery(
+        &self,
+        email: &str,
+        code_hash: &str,
+    ) -> Result<Option<Datetime>, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                format!(
+                    "UPDATE Users SET recovery = {{ code_hash: $code_hash, expires_at: time::now() + {}m, attempts: 0, sent_at: time::now() }} WHERE email = $email AND (recovery = NONE OR recovery.sent_at <= time::now() - {}s)",
+                    self.recovery_code_ttl_minutes, self.recovery_resend_seconds
+                ),
+                serde_json::json!({ "email": email, "code_hash": code_hash }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        Ok(user
+            .and_then(|user| user.recovery)
+            .map(|recovery| recovery.expires_at))
+    }
+
+    /// Counts an attempt against the user's recovery code before it is
+    /// checked, so concurrent guesses cannot share one attempt, and returns
+    /// the new count.
+    pub async fn count_recovery_attempt(&self, email: &str) -> Result<u32, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                "UPDATE Users SET recovery.attempts += 1 WHERE email = $email AND recovery != NONE"
+                    .to_string(),
+                serde_json::json!({ "email": email }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        Ok(user
+            .and_then(|user| user.recovery)
+            .map_or(u32::MAX, |recovery| recovery.attempts))
+    }
+
+    pub async fn clear_recovery(&self, email: &str) -> Result<(), Error> {
+        self.client
+            .query_bind(
+                "UPDATE Users SET recovery = NONE WHERE email = $email".to_string(),
+                serde_json::json!({ "email": email }),
+            )
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Finishes account recovery: sets the new password, clears the code
+    /// and signs the user out of any existing session.
+    pub async fn reset_password(&self, user: &User, new_password: String) -> Result<(), AuthError> {
+        self.set_password(user, new_password).await?;
+        self.client
+            .query_bind(
+                "UPDATE Users SET recovery = NONE, logged_in = false WHERE email = $email"
+                    .to_string(),
+                serde_json::json!({ "email": user.email }),
+            )
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Stores the digest of a new verification token, replacing any earlier
+    /// one, and returns when it expires. Returns `None` without issuing a
+    /// token if the account is already verified or one was sent less than
+    /// `email_verification_resend_seconds` ago.
+    pub async fn start_verification(
+        &self,
+        email: &str,
+        token_hash: &str,
+    ) -> Result<Option<Datetime>, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                format!(
+                    "UPDATE Users SET verification = {{ token_hash: $token_hash, expires_at: time::now() + {}m, sent_at: time::now() }} WHERE email = $email AND verified != true AND (verification = NONE OR verification.sent_at <= time::now() - {}s)",
+                    self.email_verification_ttl_minutes, self.email_verification_resend_seconds
+                ),
+                serde_json::json!({ "email": email, "token_hash": token_hash }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        Ok(user
+            .and_then(|user| user.verification)
+            .map(|verification| verification.expires_at))
+    }
+
+    /// Marks the account holding an unexpired token with this digest as
+    /// verified, consuming the token.
+    pub async fn verify_email(&self, token_hash: &str) -> Result<Option<User>, Error> {
+        let query = format!(
+            "UPDATE Users SET verified = true, verification = NONE WHERE verification.token_hash = '{token_hash}' AND verification.expires_at > time::now()"
+        );
         let mut result = self.client.query(query).await?;
-        let recovery_user: Option<User> = result.take(0)?;
-        Ok(recovery_user.unwrap())
+        let user: Option<User> = result.take(0)?;
+        match user {
+            Some(user) => self.activate_if_pending(user).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Moves a newly verified account out of `pending`.
+    async fn activate_if_pending(&self, user: User) -> Result<User, Error> {
+        if user.status != AccountStatus::Pending {
+            return Ok(user);
+        }
+        Ok(self
+            .transition(&user, AccountStatus::Active, "Email verified", "")
+            .await?
+            .unwrap_or(user))
+    }
+
+    /// Records a pending move to `new_email`, replacing any earlier one, and
+    /// returns when it expires. The address must not belong to anyone else.
+    pub async fn start_email_change(
+        &self,
+        email: &str,
+        new_email: &str,
+        confirm_hash: &str,
+        cancel_hash: &str,
+    ) -> Result<Datetime, Error> {
+        if self.check_duplicate_email(new_email.to_string()).await? {
+            return Err(Error::Db(Thrown("Email already in use".to_string())));
+        }
+        let mut result = self
+            .client
+            .query_bind(
+                format!(
+                    "UPDATE Users SET email_change = {{ new_email: $new_email, confirm_hash: $confirm_hash, cancel_hash: $cancel_hash, expires_at: time::now() + {}m }} WHERE email = $email",
+                    self.email_verification_ttl_minutes
+                ),
+                serde_json::json!({
+                    "email": email,
+                    "new_email": new_email,
+                    "confirm_hash": confirm_hash,
+                    "cancel_hash": cancel_hash,
+                }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
+        user.and_then(|user| user.email_change)
+            .map(|change| change.expires_at)
+            .ok_or_else(|| Error::Db(Thrown("User not found".to_string())))
     }
 
-    pub async fn check_code(&self, username: String) -> Result<User, Error> {
-        let user = self.get_user(username.clone()).await?;
+    /// Swaps in the new email of the unexpired change with this confirm
+    /// digest, marking it verified. The address is checked again, since it
+    /// may have been taken while the change was pending.
+    pub async fn confirm_email_change(&self, confirm_hash: &str) -> Result<Option<User>, Error> {
+        let query = format!(
+            "SELECT * FROM Users WHERE email_change.confirm_hash = '{confirm_hash}' AND email_change.expires_at > time::now()"
+        );
+        let mut result = self.client.query(query).await?;
+        let user: Option<User> = result.take(0)?;
+        let Some((email, new_email)) =
+            user.and_then(|user| Some((user.email, user.email_change?.new_email)))
+        else {
+            return Ok(None);
+        };
+        if self.check_duplicate_email(new_email.clone()).await? {
+            self.client
+                .query_bind(
+                    "UPDATE Users SET email_change = NONE WHERE email = $email".to_string(),
+                    serde_json::json!({ "email": email }),
+                )
+                .await?
+                .check()?;
+            return Err(Error::Db(Thrown("Email already in use".to_string())));
+        }
+
+        let mut result = self
+            .client
+            .query_bind(
+                "UPDATE Users SET email = $new_email, verified = true, verification = NONE, email_change = NONE, previous_emails += $email WHERE email = $email AND email_change.confirm_hash = $confirm_hash"
+                    .to_string(),
+                serde_json::json!({
+                    "email": email,
+                    "new_email": new_email,
+                    "confirm_hash": confirm_hash,
+                }),
+            )
+            .await?;
+        let user: Option<User> = result.take(0)?;
         match user {
-            Some(user_value) => {
-                if user_value.recovery_code.clone().is_some() {
-                    Err(Error::Db(Thrown(
-                        "Account recovery code already exists on user".to_string(),
-                    )))
-                } else {
-                    let code = rand::thread_rng().gen_range(100_000..1_000_000);
-                    self.add_code(username, code).await
+            Some(user) => self.activate_if_pending(user).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn cancel_email_change(&self, cancel_hash: &str) -> Result<Option<User>, Error> {
+        let query = format!(
+            "UPDATE Users SET email_change = NONE WHERE email_change.cancel_hash = '{cancel_hash}'"
+        );
+        let mut result = self.client.query(query).await?;
+        result.take(0)
+    }
+
+    /// Changes a user's password, signing them out when `revoke_sessions`
+    /// is set. Callers check the current password with `check_password`
+    /// and the policy first.
+    pub async fn change_password(
+        &self,
+        user: &User,
+        new_password: String,
+        revoke_sessions: bool,
+    ) -> Result<(), AuthError> {
+        self.set_password(user, new_password).await?;
+        if revoke_sessions {
+            self.client
+                .query_bind(
+                    "UPDATE Users SET logged_in = false WHERE email = $email".to_string(),
+                    serde_json::json!({ "email": user.email }),
+                )
+                .await?
+                .check()?;
+        }
+        Ok(())
+    }
+
+    pub async fn create_api_key(
+        &self,
+        request: NewApiKey,
+        key_id: &str,
+        key_hash: &str,
+    ) -> Result<Option<ApiKeyRecord>, Error> {
+        let expires_at = match request.expires_at {
+            Some(expires_at) => match Datetime::try_from(expires_at.as_str()) {
+                Ok(date) => Some(date),
+                Err(()) => return Err(Error::Db(Thrown("Invalid expiry date".to_string()))),
+            },
+            None => None,
+        };
+
+        let mut result = self
+            .client
+            .query_bind(
+                "CREATE ApiKeys SET key_id = $key_id, name = $name, owner = $owner, key_hash = $key_hash, scopes = $scopes, created_at = time::now(), expires_at = $expires_at"
+                    .to_string(),
+                NewApiKeyRecord {
+                    key_id: key_id.to_string(),
+                    name: request.name,
+                    owner: request.owner,
+                    key_hash: key_hash.to_string(),
+                    scopes: request.scopes,
+                    expires_at,
+                },
+            )
+            .await?;
+        result.take(0)
+    }
+
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKeyRecord>, Error> {
+        let query = "SELECT * FROM ApiKeys ORDER BY created_at".to_string();
+        let mut result = self.client.query(query).await?;
+        result.take(0)
+    }
+
+    pub async fn get_api_key(&self, key_id: String) -> Result<Option<ApiKeyRecord>, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                "SELECT * FROM ApiKeys WHERE key_id = $key_id".to_string(),
+                serde_json::json!({ "key_id": key_id }),
+            )
+            .await?;
+        result.take(0)
+    }
+
+    pub async fn revoke_api_key(&self, key_id: String) -> Result<Option<ApiKeyRecord>, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                "UPDATE ApiKeys SET revoked_at = time::now() WHERE key_id = $key_id AND revoked_at = NONE"
+                    .to_string(),
+                serde_json::json!({ "key_id": key_id }),
+            )
+            .await?;
+        result.take(0)
+    }
+
+    pub async fn touch_api_key(
+        &self,
+        key_id: String,
+        upgraded_hash: Option<String>,
+    ) -> Result<(), Error> {
+        let hash_assignment = if upgraded_hash.is_some() {
+            ", key_hash = $key_hash"
+        } else {
+            ""
+        };
+        self.client
+            .query_bind(
+                format!(
+                    "UPDATE ApiKeys SET last_used_at = time::now(){hash_assignment} WHERE key_id = $key_id"
+                ),
+                serde_json::json!({ "key_id": key_id, "key_hash": upgraded_hash }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Queues `email` for delivery. Messages carrying a code pass the code's
+    /// expiry so their body is dropped once it can no longer be used.
+    pub async fn enqueue_email(
+        &self,
+        recipient: &str,
+        email: RenderedEmail,
+        expires_at: Option<&Datetime>,
+    ) -> Result<(), Error> {
+        self.client
+            .query_bind(
+                "CREATE EmailQueue SET recipient = $recipient, subject = $subject, text = $text, html = $html, status = 'pending', attempts = 0, next_attempt_at = time::now(), created_at = time::now(), expires_at = $expires_at"
+                    .to_string(),
+                NewQueuedEmail {
+                    recipient: recipient.to_string(),
+                    subject: email.subject,
+                    text: email.text,
+                    html: email.html,
+                    expires_at: expires_at.cloned(),
+                },
+            )
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    pub async fn due_emails(&self, limit: usize) -> Result<Vec<QueuedEmail>, Error> {
+        let query = format!(
+            "SELECT * FROM EmailQueue WHERE status = 'pending' AND next_attempt_at <= time::now() AND (expires_at = NONE OR expires_at > time::now()) ORDER BY next_attempt_at LIMIT {limit}"
+        );
+        let mut result = self.client.query(query).await?;
+        result.take(0)
+    }
+
+    /// Dead-letters pending messages whose code has expired and drops their
+    /// bodies, so expired codes are not kept in the queue.
+    pub async fn expire_emails(&self) -> Result<(), Error> {
+        self.client
+            .query(format!(
+                "UPDATE EmailQueue SET status = '{}', last_error = 'Code expired before delivery', text = NONE, html = NONE WHERE status = 'pending' AND expires_at != NONE AND expires_at <= time::now()",
+                EmailStatus::Dead.as_str()
+            ))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Pushes the message's next attempt past the claim period, unless
+    /// another worker already did. Returns whether this caller holds it.
+    pub async fn claim_email(&self, email: &QueuedEmail) -> Result<bool, Error> {
+        let query = format!(
+            "UPDATE {} SET next_attempt_at = time::now() + {CLAIM_SECONDS}s WHERE status = 'pending' AND next_attempt_at = <datetime>'{}'",
+            email.id,
+            email.next_attempt_at.to_raw()
+        );
+        let mut result = self.client.query(query).await?;
+        let claimed: Option<QueuedEmail> = result.take(0)?;
+        Ok(claimed.is_some())
+    }
+
+    pub async fn mark_email_sent(&self, email: &QueuedEmail) -> Result<(), Error> {
+        let query = format!(
+            "UPDATE {} SET status = 'sent', sent_at = time::now(), attempts += 1, text = NONE, html = NONE",
+            email.id
+        );
+        self.client.query(query).await?.check()?;
+        Ok(())
+    }
+
+    /// Records a failed delivery, scheduling a retry after `retry_in`
+    /// seconds or, without one, moving the message to the dead letters.
+    /// A dead message that carries a code loses its body, since resending
+    /// it would put the code back in flight.
+    pub async fn mark_email_failed(
+        &self,
+        email: &QueuedEmail,
+        attempts: u32,
+        error: &str,
+        retry_in: Option<u64>,
+    ) -> Result<(), Error> {
+        let next = match retry_in {
+            Some(seconds) => {
+                format!("status = 'pending', next_attempt_at = time::now() + {seconds}s")
+            }
+            None if email.expires_at.is_some() => format!(
+                "status = '{}', text = NONE, html = NONE",
+                EmailStatus::Dead.as_str()
+            ),
+            None => format!("status = '{}'", EmailStatus::Dead.as_str()),
+        };
+        self.client
+            .query_bind(
+                format!(
+                    "UPDATE {} SET {next}, attempts = {attempts}, last_error = $error",
+                    email.id
+                ),
+                serde_json::json!({ "error": error }),
+            )
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    pub async fn list_emails(
+        &self,
+        status: Option<EmailStatus>,
+    ) -> Result<Vec<QueuedEmail>, Error> {
+        let condition = status
+            .map(|status| format!("WHERE status = '{}'", status.as_str()))
+            .unwrap_or_default();
+        let query = format!("SELECT * FROM EmailQueue {condition} ORDER BY created_at DESC");
+        let mut result = self.client.query(query).await?;
+        result.take(0)
+    }
+
+    /// Moves a dead-lettered message back to the queue with fresh attempts.
+    /// Messages whose body was dropped cannot be retried.
+    pub async fn retry_email(&self, id: String) -> Result<Option<QueuedEmail>, Error> {
+        let mut result = self
+            .client
+            .query_bind(
+                "UPDATE type::thing('EmailQueue', $id) SET status = 'pending', attempts = 0, next_attempt_at = time::now() WHERE status = 'dead' AND text != NONE"
+                    .to_string(),
+                serde_json::json!({ "id": id }),
+            )
+            .await?;
+        result.take(0)
+    }
+
+    /// Reads one page of users for an export, in a stable order.
+    pub async fn export_users(
+        &self,
+        filter: &ExportFilter,
+        start: usize,
+    ) -> Result<Vec<User>, Error> {
+        let query = format!(
+            "SELECT * FROM Users {} ORDER BY id LIMIT {EXPORT_BATCH_SIZE} START {start}",
+            filter.to_conditions()
+        );
+        let mut result = self.client.query(query).await?;
+        result.take(0)
+    }
+
+    /// Inserts imported users in batches, applying the same duplicate email
+    /// and username rules as `signup`. Invalid rows are skipped and reported.
+    pub async fn import_users(&self, rows: Vec<ParsedRow>) -> Result<ImportReport, Error> {
+        let mut report = ImportReport::default();
+        let mut seen_emails = HashSet::new();
+        let mut seen_usernames = HashSet::new();
+        let mut rows = rows.into_iter().peekable();
+
+        while rows.peek().is_some() {
+            let mut batch = Vec::new();
+            for ParsedRow { line, row } in rows.by_ref().take(IMPORT_BATCH_SIZE) {
+                let checked = match row {
+                    Ok(row) => {
+                        self.check_import_row(row, &mut seen_emails, &mut seen_usernames)
+                            .await
+                    }
+                    Err(error) => Err(error),
+                };
+                match checked {
+                    Ok(row) => batch.push((line, row)),
+                    Err(error) => report.errors.push(ImportRowError { line, error }),
                 }
             }
-            None => Err(Error::Db(Thrown("User doesn't exists".to_string()))),
+
+            let mut result = self
+                .client
+                .query_bind(
+                    "SELECT email, username FROM Users WHERE email INSIDE $emails OR username INSIDE $usernames".to_string(),
+                    serde_json::json!({
+                        "emails": batch.iter().map(|(_, row)| &row.email).collect::<Vec<_>>(),
+                        "usernames": batch.iter().map(|(_, row)| &row.username).collect::<Vec<_>>(),
+                    }),
+                )
+                .await?;
+            let existing: Vec<ExistingIdentity> = result.take(0)?;
+            let taken_emails: HashSet<_> = existing.iter().map(|user| &user.email).collect();
+            let taken_usernames: HashSet<_> = existing.iter().map(|user| &user.username).collect();
+            let (taken_emails, taken_usernames) = (&taken_emails, &taken_usernames);
+
+            let hashed: Vec<_> = stream::iter(batch.into_iter().map(|(line, row)| async move {
+                if taken_emails.contains(&row.email) {
+                    return Err((line, "Email already in use".to_string()));
+                }
+                if taken_usernames.contains(&row.username) {
+                    return Err((line, "Username already taken".to_string()));
+                }
+                let password = match (row.password, row.password_hash) {
+                    (_, Some(password_hash)) => password_hash,
+                    (Some(password), None) => self
+                        .hash_import_password(password)
+                        .await
+                        .map_err(|err| (line, err))?,
+                    (None, None) => unreachable!("validated above"),
+                };
+                Ok(NewUserRecord {
+                    email: row.email,
+                    username: row.username,
+                    password,
+                    logged_in: false,
+                    // Imported accounts were in use elsewhere and get no
+                    // verification email, so they start out verified.
+                    verified: true,
+                    status: AccountStatus::Active,
+                })
+            }))
+            .buffer_unordered(IMPORT_HASH_CONCURRENCY)
+            .collect()
+            .await;
+
+            let mut users = Vec::new();
+            for result in hashed {
+                match result {
+                    Ok(user) => users.push(user),
+                    Err((line, error)) => report.errors.push(ImportRowError { line, error }),
+                }
+            }
+            if users.is_empty() {
+                continue;
+            }
+
+            let inserted = users.len();
+            self.client
+                .query_bind(
+                    "INSERT INTO Users (SELECT *, time::now() AS created_at, time::now() AS status_changed_at, [{ from: NONE, to: 'active', reason: 'Imported', at: time::now() }] AS status_history FROM $users)"
+                        .to_string(),
+                    serde_json::json!({ "users": users }),
+                )
+                .await?
+                .check()?;
+            report.imported += inserted;
+        }
+
+        report.errors.sort_by_key(|error| error.line);
+        Ok(report)
+    }
+
+    /// Hashes an imported password. A busy hasher is waited out rather
+    /// than failing the row, since the row itself is fine.
+    async fn hash_import_password(&self, password: String) -> Result<String, String> {
+        loop {
+            match self.hasher.hash(password.clone()).await {
+                Err(HashError::Busy) => sleep(IMPORT_BUSY_RETRY).await,
+                result => return result.map_err(|err| err.to_string()),
+            }
+        }
+    }
+
+    /// Validates one import row against the password policy, reserved
+    /// usernames and the rows before it in the same import.
+    async fn check_import_row(
+        &self,
+        row: ImportRow,
+        seen_emails: &mut HashSet<String>,
+        seen_usernames: &mut HashSet<String>,
+    ) -> Result<ImportRow, String> {
+        validate_import_row(&row)?;
+        if let Some(password) = &row.password {
+            let violations = self
+                .password_policy
+                .check(password, &row.username, &row.email)
+                .await;
+            if !violations.is_empty() {
+                return Err(violations
+                    .into_iter()
+                    .map(|violation| violation.message)
+                    .collect::<Vec<_>>()
+                    .join("; "));
+            }
+        }
+        if !seen_emails.insert(row.email.clone()) {
+            return Err("Email already in use".to_string());
+        }
+        if !seen_usernames.insert(row.username.clone()) {
+            return Err("Username already taken".to_string());
+        }
+        if self
+            .is_username_reserved(&row.username, None)
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            return Err(USERNAME_RESERVED.to_string());
+        }
+        Ok(row)
+    }
+}
+
+fn validate_import_row(row: &ImportRow) -> Result<(), String> {
+    if row.email.is_empty() || !row.email.contains('@') {
+        return Err("Email is invalid".to_string());
+    }
+    if row.username.is_empty() {
+        return Err("Username is required".to_string());
+    }
+    match (&row.password, &row.password_hash) {
+        (Some(_), Some(_)) => Err("Give either password or password_hash, not both".to_string()),
+        (None, None) => Err("Password or password_hash is required".to_string()),
+        (_, Some(password_hash)) if !is_supported_hash(password_hash) => {
+            Err("Password hash format is not supported".to_string())
         }
+        _ => Ok(()),
     }
 }
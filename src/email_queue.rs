@@ -0,0 +1,202 @@
+//! Outbound email queue. Routes enqueue rendered emails in the database;
+//! a background worker started at liftoff delivers them, retrying failures
+//! with exponential backoff until `email_max_attempts` is reached, after
+//! which the message is dead-lettered for an admin to inspect and retry.
+//! Messages carrying a code are dead-lettered without their body once the
+//! code expires, and cannot be retried after dying.
+
+use crate::{
+    database::Database,
+    error::ApiError,
+    mailer::Mailer,
+    models::{QueuedEmail, User},
+    settings::Settings,
+    templates::RenderedEmail,
+};
+use rocket::{
+    fairing::AdHoc,
+    http::Status,
+    tokio::{
+        select,
+        time::{sleep, Duration},
+    },
+    Shutdown,
+};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Datetime;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+const DEFAULT_RETRY_BASE_SECONDS: u64 = 30;
+const MAX_RETRY_DELAY_SECONDS: u64 = 6 * 60 * 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: usize = 50;
+/// How long a worker holds a claimed message before another may retry it.
+pub const CLAIM_SECONDS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailStatus {
+    Pending,
+    Sent,
+    Dead,
+}
+
+impl EmailStatus {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(EmailStatus::Pending),
+            "sent" => Some(EmailStatus::Sent),
+            "dead" => Some(EmailStatus::Dead),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EmailStatus::Pending => "pending",
+            EmailStatus::Sent => "sent",
+            EmailStatus::Dead => "dead",
+        }
+    }
+}
+
+/// Renders template `name` for `user` and queues it for delivery. Emails
+/// carrying a code pass when it expires, so the queue drops them after.
+pub async fn queue_email(
+    db: &Database,
+    mailer: &Mailer,
+    user: &User,
+    name: &str,
+    variables: &[(&str, &str)],
+    expires_at: Option<&Datetime>,
+) -> Result<(), ApiError> {
+    queue_email_to(db, mailer, user, &user.email, name, variables, expires_at).await
+}
+
+/// Like `queue_email`, but to an address other than the user's own.
+pub async fn queue_email_to(
+    db: &Database,
+    mailer: &Mailer,
+    user: &User,
+    recipient: &str,
+    name: &str,
+    variables: &[(&str, &str)],
+    expires_at: Option<&Datetime>,
+) -> Result<(), ApiError> {
+    let email = mailer
+        .render(name, user.locale.as_deref(), variables)
+        .map_err(|err| ApiError::new(Status::InternalServerError, &err))?;
+    db.enqueue_email(recipient, email, expires_at).await?;
+    Ok(())
+}
+
+struct Worker {
+    db: Database,
+    mailer: Mailer,
+    max_attempts: u32,
+    retry_base_seconds: u64,
+}
+
+/// The fairing that starts the delivery worker once the server is up. It
+/// does nothing when no mail transport is configured.
+pub fn worker(db: Database, mailer: Mailer, settings: &Settings) -> AdHoc {
+    let worker = Worker {
+        db,
+        mailer,
+        max_attempts: settings.email_max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+        retry_base_seconds: settings
+            .email_retry_base_seconds
+            .unwrap_or(DEFAULT_RETRY_BASE_SECONDS),
+    };
+    AdHoc::on_liftoff("Email queue", move |rocket| {
+        Box::pin(async move {
+            if worker.mailer.is_enabled() {
+                rocket::tokio::spawn(worker.run(rocket.shutdown()));
+            }
+        })
+    })
+}
+
+impl Worker {
+    async fn run(self, mut shutdown: Shutdown) {
+        loop {
+            self.deliver_due().await;
+            select! {
+                () = sleep(POLL_INTERVAL) => {}
+                () = &mut shutdown => break,
+            }
+        }
+    }
+
+    async fn deliver_due(&self) {
+        if let Err(err) = self.db.expire_emails().await {
+            rocket::warn!("Failed to expire queued emails: {err}");
+        }
+        let Ok(emails) = self.db.due_emails(BATCH_SIZE).await else {
+            return;
+        };
+        for email in emails {
+            // Another worker may have claimed it since it was listed.
+            if !self.db.claim_email(&email).await.unwrap_or(false) {
+                continue;
+            }
+            self.deliver(email).await;
+        }
+    }
+
+    async fn deliver(&self, email: QueuedEmail) {
+        let rendered = RenderedEmail {
+            subject: email.subject.clone(),
+            text: email.text.clone().unwrap_or_default(),
+            html: email.html.clone(),
+        };
+        // Failing to record the outcome leaves the claim to expire, so the
+        // message is retried rather than lost.
+        let _ = match self.mailer.send(&email.recipient, rendered).await {
+            Ok(()) => self.db.mark_email_sent(&email).await,
+            Err(error) => {
+                let attempts = email.attempts + 1;
+                let retry_in = (attempts < self.max_attempts)
+                    .then(|| backoff(self.retry_base_seconds, attempts));
+                self.db
+                    .mark_email_failed(&email, attempts, &error, retry_in)
+                    .await
+            }
+        };
+    }
+}
+
+/// `retry_base_seconds` doubled for each attempt after the first.
+fn backoff(retry_base_seconds: u64, attempts: u32) -> u64 {
+    let factor = 1_u64.checked_shl(attempts - 1).unwrap_or(u64::MAX);
+    retry_base_seconds
+        .saturating_mul(factor)
+        .min(MAX_RETRY_DELAY_SECONDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_per_attempt() {
+        assert_eq!(backoff(30, 1), 30);
+        assert_eq!(backoff(30, 2), 60);
+        assert_eq!(backoff(30, 5), 480);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff(30, 12), MAX_RETRY_DELAY_SECONDS);
+        assert_eq!(backoff(30, 64), MAX_RETRY_DELAY_SECONDS);
+        assert_eq!(backoff(u64::MAX, 2), MAX_RETRY_DELAY_SECONDS);
+    }
+
+    #[test]
+    fn status_names_round_trip() {
+        for status in [EmailStatus::Pending, EmailStatus::Sent, EmailStatus::Dead] {
+            assert_eq!(EmailStatus::from_name(status.as_str()), Some(status));
+        }
+        assert_eq!(EmailStatus::from_name("queued"), None);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{hash::HashError, responses::PolicyViolation};
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::json::Json,
+    Request,
+};
+use serde::Serialize;
+use std::fmt;
+use surrealdb::{error::Db::Thrown, Error};
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub error: Error,
+    pub violations: Vec<PolicyViolation>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    #[serde(flatten)]
+    error: Error,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    violations: Vec<PolicyViolation>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, message: &str) -> Self {
+        ApiError {
+            status,
+            error: Error::Db(Thrown(message.to_string())),
+            violations: Vec::new(),
+        }
+    }
+
+    pub fn unauthorized(message: &str) -> Self {
+        ApiError::new(Status::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: &str) -> Self {
+        ApiError::new(Status::Forbidden, message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        ApiError::new(Status::NotFound, message)
+    }
+
+    pub fn password_policy(violations: Vec<PolicyViolation>) -> Self {
+        ApiError {
+            violations,
+            ..ApiError::new(
+                Status::UnprocessableEntity,
+                "Password does not meet the password policy",
+            )
+        }
+    }
+}
+
+/// Why a request with valid input was refused: the account may not be
+/// used, or the server is too busy to check a password. `Db` carries any
+/// other error met on the way.
+#[derive(Debug)]
+pub enum AuthError {
+    Suspended,
+    Locked,
+    PendingDeletion,
+    Unverified,
+    Busy,
+    Db(Error),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Suspended => f.write_str("Account is suspended"),
+            AuthError::Locked => {
+                f.write_str("Account is locked after too many failed logins, try again later")
+            }
+            AuthError::PendingDeletion => f.write_str("Account is pending deletion"),
+            AuthError::Unverified => f.write_str("Email address is not verified"),
+            AuthError::Busy => HashError::Busy.fmt(f),
+            AuthError::Db(error) => error.fmt(f),
+        }
+    }
+}
+
+impl From<Error> for AuthError {
+    fn from(error: Error) -> Self {
+        AuthError::Db(error)
+    }
+}
+
+impl From<HashError> for AuthError {
+    fn from(error: HashError) -> Self {
+        match error {
+            HashError::Busy => AuthError::Busy,
+            HashError::Failed(message) => AuthError::Db(Error::Db(Thrown(message))),
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(error: AuthError) -> Self {
+        let status = match error {
+            AuthError::Db(error) => return ApiError::from(error),
+            AuthError::Busy => Status::ServiceUnavailable,
+            AuthError::Suspended
+            | AuthError::Locked
+            | AuthError::PendingDeletion
+            | AuthError::Unverified => Status::Forbidden,
+        };
+        ApiError::new(status, &error.to_string())
+    }
+}
+
+impl From<HashError> for ApiError {
+    fn from(error: HashError) -> Self {
+        ApiError::from(AuthError::from(error))
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        let status = match &error {
+            Error::Db(Thrown(_)) => Status::BadRequest,
+            _ => Status::InternalServerError,
+        };
+        ApiError {
+            status,
+            error,
+            violations: Vec::new(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = ErrorBody {
+            error: self.error,
+            violations: self.violations,
+        };
+        (self.status, Json(body)).respond_to(request)
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{account::AccountStatus, responses::ExportedUser};
+use rocket::http::ContentType;
+use surrealdb::sql::Datetime;
+
+pub const EXPORT_BATCH_SIZE: usize = 500;
+
+pub enum ExportFormat {
+    Csv,
+    JsonLines,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: Option<&str>) -> Option<Self> {
+        match name {
+            Some("csv") => Some(ExportFormat::Csv),
+            Some("jsonl" | "ndjson") | None => Some(ExportFormat::JsonLines),
+            Some(_) => None,
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            ExportFormat::Csv => ContentType::CSV,
+            ExportFormat::JsonLines => ContentType::new("application", "x-ndjson"),
+        }
+    }
+}
+
+/// Which users an export covers. Creation bounds are inclusive; users
+/// created before `created_at` was recorded only match unbounded exports.
+pub struct ExportFilter {
+    pub created_after: Option<Datetime>,
+    pub created_before: Option<Datetime>,
+    pub logged_in: Option<bool>,
+    pub status: Option<AccountStatus>,
+    pub include_password_hashes: bool,
+}
+
+impl ExportFilter {
+    pub fn to_conditions(&self) -> String {
+        let mut conditions = Vec::new();
+        if let Some(after) = &self.created_after {
+            conditions.push(format!("created_at >= <datetime>'{}'", after.to_raw()));
+        }
+        if let Some(before) = &self.created_before {
+            conditions.push(format!("created_at <= <datetime>'{}'", before.to_raw()));
+        }
+        if let Some(logged_in) = self.logged_in {
+            conditions.push(format!("logged_in = {logged_in}"));
+        }
+        if let Some(status) = self.status {
+            conditions.push(format!("status = '{}'", status.as_str()));
+        }
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        }
+    }
+}
+
+pub fn parse_date(value: Option<String>) -> Result<Option<Datetime>, String> {
+    value
+        .map(|value| {
+            Datetime::try_from(value.as_str()).map_err(|()| format!("Invalid date {value:?}"))
+        })
+        .transpose()
+}
+
+/// Encodes one page of users. The CSV header is written with the first page.
+pub fn encode(
+    format: &ExportFormat,
+    users: &[ExportedUser],
+    first_page: bool,
+) -> Result<String, String> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(first_page)
+                .from_writer(Vec::new());
+            for user in users {
+                writer.serialize(user).map_err(|err| err.to_string())?;
+            }
+            let bytes = writer.into_inner().map_err(|err| err.to_string())?;
+            String::from_utf8(bytes).map_err(|err| err.to_string())
+        }
+        ExportFormat::JsonLines => users.iter().try_fold(String::new(), |mut lines, user| {
+            lines.push_str(&serde_json::to_string(user).map_err(|err| err.to_string())?);
+            lines.push('\n');
+            Ok(lines)
+        }),
+    }
+}
@@ -1,21 +1,465 @@
+use crate::settings::{Argon2Variant, Settings};
 use argon2::{
-    password_hash::{rand_core::OsRng, Error, SaltString},
-    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{errors::InvalidValue, rand_core::OsRng, Error, Output, SaltString},
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, PasswordHasher,
+    PasswordVerifier, Version,
 };
+use pbkdf2::{pbkdf2_hmac, Pbkdf2};
+use rocket::tokio::{
+    sync::Semaphore,
+    task,
+    time::{timeout, Duration},
+};
+use scrypt::Scrypt;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Write},
+    fs,
+    sync::Arc,
+};
+
+/// Why `Hasher` returned no result.
+#[derive(Debug)]
+pub enum HashError {
+    /// No worker came free within `hash_queue_timeout_ms`.
+    Busy,
+    Failed(String),
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashError::Busy => f.write_str("Server is busy, try again later"),
+            HashError::Failed(message) => f.write_str(message),
+        }
+    }
+}
 
 pub fn generate_salt() -> SaltString {
     SaltString::generate(&mut OsRng)
 }
 
+#[allow(clippy::needless_pass_by_value)]
 pub fn hash_password(password: String, salt: SaltString) -> Result<String, Error> {
     let argon2 = Argon2::default();
     let password_hash = argon2.hash_password(password.as_bytes(), &salt)?;
     Ok(password_hash.to_string())
 }
 
+#[allow(clippy::needless_pass_by_value)]
 pub fn verify_password(password: String, password_hash: String) -> Result<bool, Error> {
     let hash = PasswordHash::new(&password_hash)?;
     Ok(Argon2::default()
         .verify_password(password.as_bytes(), &hash)
         .is_ok())
 }
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Environment variable holding peppers, in the same `<version>=<secret>`
+/// form as `pepper_file`, separated by newlines or commas.
+const PEPPER_ENV: &str = "RUSTAUTH_PEPPERS";
+
+type Peppers = HashMap<String, Arc<[u8]>>;
+
+/// Runs Argon2 off the async executor. At most `hash_workers` hashes run at
+/// once on the blocking pool; a request that cannot get a slot within
+/// `hash_queue_timeout_ms` fails with `HashError::Busy`, which routes answer
+/// with 503. New hashes use the Argon2 variant and costs from `Settings`.
+///
+/// When peppers are configured, new hashes are keyed with the current one
+/// and record its version in the Argon2 `keyid` parameter, so older hashes
+/// keep verifying with the pepper they were made with until they are
+/// rehashed on login.
+pub struct Hasher {
+    permits: Semaphore,
+    queue_timeout: Duration,
+    algorithm: Algorithm,
+    params: Params,
+    peppers: Peppers,
+    current_pepper: Option<Arc<[u8]>>,
+}
+
+impl Hasher {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let workers = settings.hash_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+        });
+        let algorithm = match settings.argon2_variant {
+            Some(Argon2Variant::Argon2d) => Algorithm::Argon2d,
+            Some(Argon2Variant::Argon2i) => Algorithm::Argon2i,
+            Some(Argon2Variant::Argon2id) | None => Algorithm::Argon2id,
+        };
+
+        let (peppers, current_version) = load_peppers(settings)?;
+        let mut params = ParamsBuilder::new();
+        params
+            .m_cost(
+                settings
+                    .argon2_memory_cost
+                    .unwrap_or(Params::DEFAULT_M_COST),
+            )
+            .t_cost(settings.argon2_iterations.unwrap_or(Params::DEFAULT_T_COST))
+            .p_cost(
+                settings
+                    .argon2_parallelism
+                    .unwrap_or(Params::DEFAULT_P_COST),
+            );
+        if let Some(version) = &current_version {
+            params.keyid(KeyId::new(version.as_bytes()).map_err(invalid_settings)?);
+        }
+        let params = params.build().map_err(invalid_settings)?;
+        let current_pepper = current_version.map(|version| peppers[&version].clone());
+
+        Ok(Hasher {
+            permits: Semaphore::new(workers.max(1)),
+            queue_timeout: Duration::from_millis(settings.hash_queue_timeout_ms.unwrap_or(2000)),
+            algorithm,
+            params,
+            peppers,
+            current_pepper,
+        })
+    }
+
+    /// Whether `password_hash` was produced by another algorithm, or with a
+    /// different variant, version, cost or pepper than the current settings,
+    /// and should be replaced after the next successful verification.
+    pub fn needs_rehash(&self, password_hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(password_hash) else {
+            return is_non_phc_legacy(password_hash);
+        };
+        let current = Algorithm::try_from(hash.algorithm)
+            .is_ok_and(|algorithm| algorithm == self.algorithm)
+            && hash.version == Some(Version::V0x13.into())
+            && Params::try_from(&hash).is_ok_and(|params| {
+                params.m_cost() == self.params.m_cost()
+                    && params.t_cost() == self.params.t_cost()
+                    && params.p_cost() == self.params.p_cost()
+                    && params.keyid() == self.params.keyid()
+            });
+        !current
+    }
+
+    async fn run<T, F>(&self, job: F) -> Result<T, HashError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+    {
+        let Ok(Ok(_permit)) = timeout(self.queue_timeout, self.permits.acquire()).await else {
+            return Err(HashError::Busy);
+        };
+        match task::spawn_blocking(job).await {
+            Ok(result) => result.map_err(|err| HashError::Failed(err.to_string())),
+            Err(err) => Err(HashError::Failed(err.to_string())),
+        }
+    }
+
+    pub async fn hash(&self, password: String) -> Result<String, HashError> {
+        let algorithm = self.algorithm;
+        let params = self.params.clone();
+        let pepper = self.current_pepper.clone();
+        self.run(move || {
+            let argon2 = match &pepper {
+                Some(pepper) => Argon2::new_with_secret(pepper, algorithm, Version::V0x13, params)?,
+                None => Argon2::new(algorithm, Version::V0x13, params),
+            };
+            Ok(argon2
+                .hash_password(password.as_bytes(), &generate_salt())?
+                .to_string())
+        })
+        .await
+    }
+
+    /// A hash made with a pepper that is no longer configured never
+    /// verifies, so its owner has to reset their password.
+    pub async fn verify(&self, password: String, password_hash: String) -> Result<bool, HashError> {
+        let pepper_version = PasswordHash::new(&password_hash)
+            .ok()
+            .and_then(|hash| Params::try_from(&hash).ok())
+            .map(|params| String::from_utf8_lossy(params.keyid()).into_owned())
+            .filter(|version| !version.is_empty());
+        let pepper = match pepper_version {
+            Some(version) => {
+                let Some(pepper) = self.peppers.get(&version) else {
+                    rocket::warn!("Password hash uses unknown pepper version {version}");
+                    return Ok(false);
+                };
+                Some(pepper.clone())
+            }
+            None => None,
+        };
+
+        self.run(move || {
+            if let Some(result) = verify_legacy(&password, &password_hash) {
+                return result;
+            }
+            let hash = PasswordHash::new(&password_hash)?;
+            let argon2 = match &pepper {
+                Some(pepper) => Argon2::new_with_secret(
+                    pepper,
+                    Algorithm::default(),
+                    Version::default(),
+                    Params::default(),
+                )?,
+                None => Argon2::default(),
+            };
+            Ok(argon2.verify_password(password.as_bytes(), &hash).is_ok())
+        })
+        .await
+    }
+}
+
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+const DJANGO_PBKDF2_PREFIX: &str = "pbkdf2_sha256$";
+
+/// Whether `password_hash` is in a format `Hasher::verify` understands.
+pub fn is_supported_hash(password_hash: &str) -> bool {
+    is_non_phc_legacy(password_hash)
+        || PasswordHash::new(password_hash).is_ok_and(|hash| {
+            Algorithm::try_from(hash.algorithm).is_ok()
+                || matches!(hash.algorithm.as_str(), "scrypt" | "pbkdf2-sha256")
+        })
+}
+
+fn is_non_phc_legacy(password_hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| password_hash.starts_with(prefix))
+        || password_hash.starts_with(DJANGO_PBKDF2_PREFIX)
+}
+
+/// Verifies hashes imported from other systems: bcrypt, Django's
+/// `pbkdf2_sha256$<iterations>$<salt>$<hash>`, and PHC-format scrypt and
+/// PBKDF2-SHA256. Returns `None` for anything else, which is left to Argon2.
+fn verify_legacy(password: &str, password_hash: &str) -> Option<Result<bool, Error>> {
+    if BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| password_hash.starts_with(prefix))
+    {
+        return Some(Ok(bcrypt::verify(password, password_hash).unwrap_or(false)));
+    }
+    if let Some(encoded) = password_hash.strip_prefix(DJANGO_PBKDF2_PREFIX) {
+        return Some(verify_django_pbkdf2(password, encoded));
+    }
+
+    let hash = PasswordHash::new(password_hash).ok()?;
+    match hash.algorithm.as_str() {
+        "scrypt" => Some(Ok(Scrypt
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())),
+        "pbkdf2-sha256" => Some(Ok(Pbkdf2
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())),
+        _ => None,
+    }
+}
+
+fn verify_django_pbkdf2(password: &str, encoded: &str) -> Result<bool, Error> {
+    let mut fields = encoded.splitn(3, '$');
+    let (Some(iterations), Some(salt), Some(expected)) =
+        (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(Error::PhcStringField);
+    };
+    let iterations = iterations
+        .parse()
+        .map_err(|_| Error::ParamValueInvalid(InvalidValue::Malformed))?;
+    let expected = Output::b64_decode(expected.trim_end_matches('='))?;
+
+    let mut derived = vec![0; expected.len()];
+    pbkdf2_hmac::<Sha256>(
+        password.as_bytes(),
+        salt.as_bytes(),
+        iterations,
+        &mut derived,
+    );
+    Ok(Output::new(&derived)? == expected)
+}
+
+fn invalid_settings(err: impl std::fmt::Display) -> String {
+    format!("Invalid hashing settings: {err}")
+}
+
+/// Reads `<version>=<secret>` peppers from `pepper_file` and `RUSTAUTH_PEPPERS`.
+/// The current pepper is `pepper_version`, or the last one listed.
+fn load_peppers(settings: &Settings) -> Result<(Peppers, Option<String>), String> {
+    let mut sources = Vec::new();
+    if let Some(path) = &settings.pepper_file {
+        sources.push(fs::read_to_string(path).map_err(invalid_settings)?);
+    }
+    if let Ok(value) = env::var(PEPPER_ENV) {
+        sources.push(value);
+    }
+
+    let mut peppers = HashMap::new();
+    let mut last_version = None;
+    for entry in sources.iter().flat_map(|source| source.split(['\n', ','])) {
+        let entry = entry.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        let Some((version, secret)) = entry.split_once('=') else {
+            return Err(invalid_settings(
+                "pepper entries must be <version>=<secret>",
+            ));
+        };
+        let version = version.trim();
+        if version.is_empty()
+            || version.len() > Params::MAX_KEYID_LEN
+            || !version.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(invalid_settings(format!(
+                "pepper version {version:?} must be 1-8 letters or digits"
+            )));
+        }
+        peppers.insert(version.to_string(), Arc::from(secret.trim().as_bytes()));
+        last_version = Some(version.to_string());
+    }
+
+    let current_version = settings.pepper_version.clone().or(last_version);
+    if let Some(version) = &current_version {
+        if !peppers.contains_key(version) {
+            return Err(invalid_settings(format!(
+                "unknown pepper version {version}"
+            )));
+        }
+    }
+    Ok((peppers, current_version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// From Python's `bcrypt` package, as used by the bcrypt crate's tests.
+    const BCRYPT: (&str, &str) = (
+        "correctbatteryhorsestapler",
+        "$2b$04$EGdrhbKUv8Oc9vGiXX0HQOxSg445d458Muh7DAHskb6QbtCvdxcie",
+    );
+    /// The remaining hashes were made with Python's `hashlib`. The Django one
+    /// is what `make_password` stores with salt `seasalt` at 10000 iterations.
+    const SCRYPT: (&str, &str) = (
+        "Zebra-Quilt-42",
+        "$scrypt$ln=10,r=8,p=1$cnVzdGF1dGgtc2FsdC0xNg$tM1Pscaeg87d53IN7PcRoyBmluxRjmsZcfa+bcKce10",
+    );
+    const PBKDF2: (&str, &str) = (
+        "Zebra-Quilt-42",
+        "$pbkdf2-sha256$i=1000,l=32$cnVzdGF1dGgtc2FsdC0xNg$4QA8R9dB/lNm+Z+N9A8FRl7WKJO8jOSpBPi+b9QB9GM",
+    );
+    const DJANGO: (&str, &str) = (
+        "Zebra-Quilt-42",
+        "pbkdf2_sha256$10000$seasalt$RlLtJAOPTU+B9JE1yqwL2G2nQI9iXnjwMKLOmYuw6gM=",
+    );
+
+    fn cheap() -> Settings {
+        Settings {
+            argon2_memory_cost: Some(Params::MIN_M_COST),
+            argon2_iterations: Some(1),
+            ..Settings::default()
+        }
+    }
+
+    fn peppered(version: &str) -> Settings {
+        let path = env::temp_dir().join(format!("rustauth-test-pepper-{version}"));
+        fs::write(&path, format!("{version}=secret-{version}\n")).unwrap();
+        Settings {
+            pepper_file: Some(path.to_string_lossy().into_owned()),
+            argon2_memory_cost: Some(Params::MIN_M_COST),
+            argon2_iterations: Some(1),
+            ..Settings::default()
+        }
+    }
+
+    #[rocket::async_test]
+    async fn retired_pepper_does_not_verify() {
+        let old = Hasher::from_settings(&peppered("old")).unwrap();
+        let password_hash = old.hash("password".to_string()).await.unwrap();
+        assert!(old
+            .verify("password".to_string(), password_hash.clone())
+            .await
+            .unwrap());
+
+        let current = Hasher::from_settings(&peppered("new")).unwrap();
+        assert!(!current
+            .verify("password".to_string(), password_hash)
+            .await
+            .unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn legacy_hashes_verify() {
+        let hasher = Hasher::from_settings(&cheap()).unwrap();
+        for (password, password_hash) in [BCRYPT, SCRYPT, PBKDF2, DJANGO] {
+            assert!(is_supported_hash(password_hash), "{password_hash}");
+            assert!(
+                hasher
+                    .verify(password.to_string(), password_hash.to_string())
+                    .await
+                    .unwrap(),
+                "{password_hash}"
+            );
+            assert!(
+                !hasher
+                    .verify("wrong".to_string(), password_hash.to_string())
+                    .await
+                    .unwrap(),
+                "{password_hash}"
+            );
+        }
+    }
+
+    #[test]
+    fn django_pbkdf2_parser() {
+        let (password, password_hash) = DJANGO;
+        let encoded = password_hash.strip_prefix(DJANGO_PBKDF2_PREFIX).unwrap();
+        assert!(verify_django_pbkdf2(password, encoded).unwrap());
+        assert!(!verify_django_pbkdf2("wrong", encoded).unwrap());
+        // Django pads the digest; the parser accepts it with or without.
+        assert!(verify_django_pbkdf2(password, encoded.trim_end_matches('=')).unwrap());
+        // Any other salt or iteration count gives a different digest.
+        let resalted = encoded.replace("seasalt", "seasalu");
+        assert!(!verify_django_pbkdf2(password, &resalted).unwrap());
+        let reiterated = encoded.replacen("10000", "10001", 1);
+        assert!(!verify_django_pbkdf2(password, &reiterated).unwrap());
+
+        assert!(verify_django_pbkdf2(password, "10000$seasalt").is_err());
+        assert!(verify_django_pbkdf2(password, "many$seasalt$RlLtJAOP").is_err());
+        assert!(verify_django_pbkdf2(password, "10000$seasalt$not base64!").is_err());
+    }
+
+    #[rocket::async_test]
+    async fn legacy_and_outdated_hashes_are_upgraded_to_argon2() {
+        let hasher = Hasher::from_settings(&cheap()).unwrap();
+        for (_, password_hash) in [BCRYPT, SCRYPT, PBKDF2, DJANGO] {
+            assert!(hasher.needs_rehash(password_hash), "{password_hash}");
+        }
+
+        let upgraded = hasher.hash(SCRYPT.0.to_string()).await.unwrap();
+        let parsed = PasswordHash::new(&upgraded).unwrap();
+        assert_eq!(
+            Algorithm::try_from(parsed.algorithm).unwrap(),
+            Algorithm::Argon2id
+        );
+        assert!(!hasher.needs_rehash(&upgraded));
+
+        let costlier = Hasher::from_settings(&Settings {
+            argon2_iterations: Some(2),
+            ..cheap()
+        })
+        .unwrap();
+        assert!(costlier.needs_rehash(&upgraded));
+        let argon2i = Hasher::from_settings(&Settings {
+            argon2_variant: Some(Argon2Variant::Argon2i),
+            ..cheap()
+        })
+        .unwrap();
+        assert!(argon2i.needs_rehash(&upgraded));
+    }
+}
@@ -0,0 +1,83 @@
+use crate::models::ImportRow;
+use rocket::http::ContentType;
+
+pub const IMPORT_BATCH_SIZE: usize = 500;
+/// How many plaintext passwords an import hashes at once, leaving the rest
+/// of the hash workers to live logins.
+pub const IMPORT_HASH_CONCURRENCY: usize = 2;
+
+pub enum ImportFormat {
+    Csv,
+    JsonLines,
+}
+
+impl ImportFormat {
+    pub fn from_content_type(content_type: &ContentType) -> Option<Self> {
+        match (content_type.top().as_str(), content_type.sub().as_str()) {
+            ("text", "csv") => Some(ImportFormat::Csv),
+            ("application", "x-ndjson" | "jsonl" | "x-jsonlines") => Some(ImportFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed input row, tagged with the line it came from so errors can be
+/// reported against the caller's file.
+pub struct ParsedRow {
+    pub line: usize,
+    pub row: Result<ImportRow, String>,
+}
+
+/// Parses CSV with an `email,username,password,password_hash` header, or one
+/// JSON object per line. Rows that fail to parse are kept with their error.
+pub fn parse(format: &ImportFormat, body: &str) -> Vec<ParsedRow> {
+    match format {
+        ImportFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(body.as_bytes());
+            let headers = match reader.headers() {
+                Ok(headers) => headers.clone(),
+                Err(err) => {
+                    return vec![ParsedRow {
+                        line: 1,
+                        row: Err(err.to_string()),
+                    }]
+                }
+            };
+
+            let mut rows = Vec::new();
+            let mut record = csv::StringRecord::new();
+            loop {
+                let line = usize::try_from(reader.position().line()).unwrap_or(0);
+                match reader.read_record(&mut record) {
+                    Ok(false) => break,
+                    Ok(true) => rows.push(ParsedRow {
+                        line,
+                        row: record
+                            .deserialize(Some(&headers))
+                            .map_err(|err| err.to_string()),
+                    }),
+                    Err(err) => {
+                        rows.push(ParsedRow {
+                            line,
+                            row: Err(err.to_string()),
+                        });
+                        break;
+                    }
+                }
+            }
+            rows
+        }
+        ImportFormat::JsonLines => body
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| ParsedRow {
+                line: index + 1,
+                row: serde_json::from_str(line).map_err(|err| err.to_string()),
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{
+    settings::{MailTransportKind, Settings, SmtpTls},
+    templates::{EmailTemplates, RenderedEmail},
+};
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use std::{fs, sync::Arc};
+
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+}
+
+/// Sends account mail through the transport chosen by `mail_transport`:
+/// an SMTP relay, the local `sendmail` binary, or a directory that each
+/// message is written to as an `.eml` file, for development and tests.
+/// With no transport configured the mailer is disabled and callers fall
+/// back to returning codes to the API client.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Option<Arc<Transport>>,
+    from: Option<Mailbox>,
+    templates: EmailTemplates,
+}
+
+impl Mailer {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let templates = EmailTemplates::from_settings(settings)?;
+        let Some(kind) = &settings.mail_transport else {
+            return Ok(Mailer {
+                transport: None,
+                from: None,
+                templates,
+            });
+        };
+        let from = settings
+            .mail_from
+            .as_deref()
+            .ok_or_else(|| invalid_settings("mail_from is required to send mail"))?
+            .parse::<Mailbox>()
+            .map_err(invalid_settings)?;
+
+        let transport = match kind {
+            MailTransportKind::Smtp => {
+                let host = settings
+                    .smtp_host
+                    .as_deref()
+                    .ok_or_else(|| invalid_settings("smtp_host is required for SMTP"))?;
+                let mut builder = match settings.smtp_tls.unwrap_or(SmtpTls::StartTls) {
+                    SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                        .map_err(invalid_settings)?,
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)
+                        .map_err(invalid_settings)?,
+                    SmtpTls::Plain => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+                };
+                if let Some(port) = settings.smtp_port {
+                    builder = builder.port(port);
+                }
+                if let (Some(username), Some(password)) =
+                    (&settings.smtp_username, &settings.smtp_password)
+                {
+                    builder =
+                        builder.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                Transport::Smtp(builder.build())
+            }
+            MailTransportKind::Sendmail => Transport::Sendmail(match &settings.sendmail_command {
+                Some(command) => AsyncSendmailTransport::new_with_command(command),
+                None => AsyncSendmailTransport::new(),
+            }),
+            MailTransportKind::File => {
+                let dir = settings.mail_file_dir.as_deref().unwrap_or("mail");
+                fs::create_dir_all(dir).map_err(invalid_settings)?;
+                Transport::File(AsyncFileTransport::new(dir))
+            }
+        };
+
+        Ok(Mailer {
+            transport: Some(Arc::new(transport)),
+            from: Some(from),
+            templates,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /// Renders template `name` in the recipient's locale, falling back as
+    /// described in `templates`.
+    pub fn render(
+        &self,
+        name: &str,
+        locale: Option<&str>,
+        variables: &[(&str, &str)],
+    ) -> Result<RenderedEmail, String> {
+        self.templates.render(name, locale, variables)
+    }
+
+    pub async fn send(&self, to: &str, email: RenderedEmail) -> Result<(), String> {
+        let (Some(transport), Some(from)) = (&self.transport, &self.from) else {
+            return Err("Mail is not configured".to_string());
+        };
+        let to = to
+            .parse::<Mailbox>()
+            .map_err(|err| format!("Invalid recipient {to}: {err}"))?;
+        let builder = Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(email.subject);
+        let message = match email.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(email.text, html)),
+            None => builder.body(email.text),
+        }
+        .map_err(|err| err.to_string())?;
+
+        match transport.as_ref() {
+            Transport::Smtp(smtp) => smtp.send(message).await.map(|_| ()).map_err(mail_error),
+            Transport::Sendmail(sendmail) => sendmail.send(message).await.map_err(mail_error),
+            Transport::File(file) => file.send(message).await.map(|_| ()).map_err(mail_error),
+        }
+    }
+}
+
+fn mail_error(err: impl std::fmt::Display) -> String {
+    format!("Failed to send mail: {err}")
+}
+
+fn invalid_settings(err: impl std::fmt::Display) -> String {
+    format!("Invalid mail settings: {err}")
+}
@@ -3,15 +3,32 @@
 #[macro_use]
 extern crate rocket;
 
-use rocket::{tokio::task::block_in_place, Build, Rocket};
+use rocket::{fairing::AdHoc, tokio::task::block_in_place, Build, Rocket};
 
+mod account;
+mod api_key;
+mod breach;
+mod cli;
 mod database;
+mod email_queue;
+mod error;
+mod export;
 mod hash;
+mod import;
+mod mailer;
 mod models;
+mod policy;
+mod purge;
+mod responses;
 mod routes;
 mod settings;
+mod templates;
+mod v1;
+mod verification;
 use {
+    api_key::KeyVerifier,
     database::Database,
+    mailer::Mailer,
     routes::{
         delete_user, email_login, get_user, root, signout, signup, username_login,
     },
@@ -20,13 +37,12 @@ use {
 
 #[launch]
 async fn rocket() -> Rocket<Build> {
-    let mut db_settings = Settings {
-        root_user: None,
-        database_type: None,
-        database_endpoint: None,
-        api_key: None,
-        root_password: None,
-    };
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = block_in_place(|| cli::run(&args)) {
+        std::process::exit(code);
+    }
+
+    let mut db_settings = Settings::default();
     let mut password = String::default();
     block_in_place(|| {
         (db_settings, password) = check_json();
@@ -35,6 +51,7 @@ async fn rocket() -> Rocket<Build> {
     let db = Database::new(db_settings.clone(), password)
         .await
         .expect("Error connecting to database");
+    let mailer = Mailer::from_settings(&db_settings).expect("Error configuring mail");
     rocket::build()
         .mount(
             "/",
@@ -48,6 +65,60 @@ async fn rocket() -> Rocket<Build> {
                 signout,
             ],
         )
+        .mount(
+            "/v1",
+            routes![
+                v1::create_user,
+                v1::import_users,
+                v1::export_users,
+                v1::get_user,
+                v1::update_user,
+                v1::delete_user,
+                v1::restore_user,
+                v1::suspend_user,
+                v1::unsuspend_user,
+                v1::unlock_user,
+                v1::status_history,
+                v1::change_username,
+                v1::username_history,
+                v1::export_user_data,
+                v1::change_password,
+                v1::request_email_change,
+                v1::confirm_email_change,
+                v1::cancel_email_change,
+                v1::create_session,
+                v1::delete_session,
+                v1::request_recovery,
+                v1::reset_password,
+                v1::verify_email,
+                v1::resend_verification,
+                v1::create_api_key,
+                v1::list_api_keys,
+                v1::revoke_api_key,
+                v1::list_emails,
+                v1::retry_email,
+            ],
+        )
+        .attach(AdHoc::on_response("Legacy route deprecation", |req, res| {
+            Box::pin(async move {
+                let path = req.uri().path();
+                if path != "/" && !path.starts_with("/v1/") {
+                    res.set_raw_header("Deprecation", "true");
+                    res.set_raw_header("Link", "</v1>; rel=\"successor-version\"");
+                }
+            })
+        }))
+        .attach(purge::worker(db.clone()))
+        .attach(email_queue::worker(
+            db.clone(),
+            mailer.clone(),
+            &db_settings,
+        ))
         .manage(db)
-        .manage(db_settings.api_key.unwrap())
+        .manage(mailer)
+        .manage(KeyVerifier::new(
+            db_settings.api_key_secret.as_deref().unwrap(),
+            db_settings.api_key.clone().unwrap(),
+        ))
+        .manage(db_settings)
 }
@@ -1,38 +1,215 @@
-use serde::{Deserialize, Serialize};
+use crate::{account::AccountStatus, api_key::Scope, email_queue::EmailStatus};
+use serde::Deserialize;
+use surrealdb::sql::{Datetime, Thing};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct User {
     pub email: String,
     pub username: String,
     pub password: String,
     pub logged_in: bool,
-    pub recovery_code: Option<i32>
+    pub recovery: Option<RecoveryCode>,
+    pub created_at: Option<Datetime>,
+    #[serde(default)]
+    pub password_history: Vec<String>,
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub verified: bool,
+    pub verification: Option<EmailVerification>,
+    pub email_change: Option<EmailChange>,
+    #[serde(default)]
+    pub previous_usernames: Vec<PreviousUsername>,
+    #[serde(default)]
+    pub previous_emails: Vec<String>,
+    pub deleted_at: Option<Datetime>,
+    pub purge_after: Option<Datetime>,
+    pub status: AccountStatus,
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<Datetime>,
+    #[serde(default)]
+    pub status_history: Vec<StatusChange>,
+    #[serde(default)]
+    pub failed_logins: u32,
+    pub locked_until: Option<Datetime>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// One entry in a user's status history. `from` is empty for the status
+/// an account was created with.
+#[derive(Debug, Deserialize)]
+pub struct StatusChange {
+    pub from: Option<AccountStatus>,
+    pub to: AccountStatus,
+    pub reason: String,
+    pub at: Datetime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreviousUsername {
+    pub username: String,
+    pub changed_at: Datetime,
+}
+
+/// A pending email verification. The record also holds a keyed digest of
+/// the token, which only queries read.
+#[derive(Debug, Deserialize)]
+pub struct EmailVerification {
+    pub expires_at: Datetime,
+    pub sent_at: Option<Datetime>,
+}
+
+/// A requested move to `new_email`, waiting for the new address to confirm.
+/// Digests of the confirm and cancel tokens are stored alongside it.
+#[derive(Debug, Deserialize)]
+pub struct EmailChange {
+    pub new_email: String,
+    pub expires_at: Datetime,
+}
+
+/// A pending account recovery. Only a keyed digest of the code is stored.
+#[derive(Debug, Deserialize)]
+pub struct RecoveryCode {
+    pub code_hash: String,
+    pub expires_at: Datetime,
+    pub attempts: u32,
+}
+
+#[derive(Debug, Deserialize)]
 pub struct SignUp {
     pub email: String,
     pub username: String,
     pub password: String,
-    pub api_key: String
+    pub locale: Option<String>,
+    pub api_key: Option<String>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct EmailLogin {
     pub email: String,
     pub password: String,
-    pub api_key: String
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LoginSuccess {
-    pub email: String,
-    pub username: String    
+    pub api_key: Option<String>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct UsernameLogin {
     pub username: String,
     pub password: String,
-    pub api_key: String
+    pub api_key: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserUpdate {
+    pub email: Option<String>,
+    pub username: Option<String>,
+    pub locale: Option<String>,
+    pub api_key: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSession {
+    pub email: Option<String>,
+    pub username: Option<String>,
+    pub password: String,
+    pub api_key: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecoveryRequest {
+    pub email: Option<String>,
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerificationResend {
+    pub email: Option<String>,
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailChangeRequest {
+    pub new_email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailChangeToken {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsernameChange {
+    pub new_username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatusChangeRequest {
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+    #[serde(default)]
+    pub revoke_sessions: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordReset {
+    pub email: Option<String>,
+    pub username: Option<String>,
+    pub code: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyRecord {
+    pub key_id: String,
+    pub name: String,
+    pub owner: String,
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: Datetime,
+    pub expires_at: Option<Datetime>,
+    pub last_used_at: Option<Datetime>,
+    pub revoked_at: Option<Datetime>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub owner: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportRow {
+    pub email: String,
+    pub username: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+}
+
+/// A message in the outbound queue. The body is cleared once it is sent,
+/// and for messages carrying a code, once it dies or `expires_at` passes.
+#[derive(Debug, Deserialize)]
+pub struct QueuedEmail {
+    pub id: Thing,
+    pub recipient: String,
+    pub subject: String,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub status: EmailStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Datetime,
+    pub created_at: Datetime,
+    pub sent_at: Option<Datetime>,
+    pub expires_at: Option<Datetime>,
 }
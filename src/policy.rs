@@ -0,0 +1,294 @@
+use crate::{breach::BreachList, responses::PolicyViolation, settings::Settings};
+use std::{fs, sync::Arc};
+
+const DEFAULT_MIN_LENGTH: usize = 8;
+/// Argon2 accepts far longer inputs, but there is no reason to spend a
+/// hashing slot on a megabyte of password.
+const DEFAULT_MAX_LENGTH: usize = 128;
+/// Usernames and email local parts shorter than this are too likely to
+/// appear in a password by chance to be worth rejecting.
+const MIN_IDENTITY_MATCH_LENGTH: usize = 3;
+
+/// Rules every new password must satisfy, from the `password_*` settings.
+/// `check` reports every rule a password breaks rather than stopping at the
+/// first, so clients can show them all at once.
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    required_classes: Vec<CharClass>,
+    min_entropy_bits: Option<f64>,
+    banned_words: Vec<String>,
+    breach_list: Option<Arc<BreachList>>,
+}
+
+impl PasswordPolicy {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let min_length = settings.password_min_length.unwrap_or(DEFAULT_MIN_LENGTH);
+        let max_length = settings.password_max_length.unwrap_or(DEFAULT_MAX_LENGTH);
+        if min_length > max_length {
+            return Err(
+                "Invalid password policy: password_min_length exceeds password_max_length"
+                    .to_string(),
+            );
+        }
+
+        let mut banned_words = settings.password_banned_words.clone().unwrap_or_default();
+        if let Some(path) = &settings.password_banned_words_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|err| format!("Invalid password policy: {path}: {err}"))?;
+            banned_words.extend(contents.lines().map(str::to_string));
+        }
+        let mut banned_words: Vec<String> = banned_words
+            .iter()
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .collect();
+        banned_words.sort();
+        banned_words.dedup();
+
+        let required_classes = [
+            (settings.password_require_lowercase, CharClass::Lowercase),
+            (settings.password_require_uppercase, CharClass::Uppercase),
+            (settings.password_require_digit, CharClass::Digit),
+            (settings.password_require_symbol, CharClass::Symbol),
+        ]
+        .into_iter()
+        .filter(|(required, _)| required.unwrap_or(false))
+        .map(|(_, class)| class)
+        .collect();
+
+        Ok(PasswordPolicy {
+            min_length,
+            max_length,
+            required_classes,
+            min_entropy_bits: settings.password_min_entropy_bits,
+            banned_words,
+            breach_list: BreachList::open(
+                settings.breached_passwords_file.as_deref(),
+                settings.breached_passwords_filter.as_deref(),
+            )?
+            .map(Arc::new),
+        })
+    }
+
+    pub async fn check(&self, password: &str, username: &str, email: &str) -> Vec<PolicyViolation> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+        let lowered = password.to_lowercase();
+
+        if length < self.min_length {
+            violations.push(PolicyViolation::new(
+                "min_length",
+                format!("Password must be at least {} characters", self.min_length),
+            ));
+        }
+        if length > self.max_length {
+            violations.push(PolicyViolation::new(
+                "max_length",
+                format!("Password must be at most {} characters", self.max_length),
+            ));
+        }
+
+        let mut classes: Vec<CharClass> = password.chars().map(CharClass::of).collect();
+        classes.sort();
+        classes.dedup();
+        for class in &self.required_classes {
+            if !classes.contains(class) {
+                violations.push(class.violation());
+            }
+        }
+
+        if let Some(min_bits) = self.min_entropy_bits {
+            if entropy_bits(&classes, length) < min_bits {
+                violations.push(PolicyViolation::new(
+                    "entropy",
+                    "Password is too easy to guess",
+                ));
+            }
+        }
+        if self
+            .banned_words
+            .iter()
+            .any(|word| lowered.contains(word.as_str()))
+        {
+            violations.push(PolicyViolation::new(
+                "banned_word",
+                "Password contains a common word or phrase",
+            ));
+        }
+        let breached = match &self.breach_list {
+            Some(breach_list) => breach_list.contains(password).await,
+            None => false,
+        };
+        if breached {
+            violations.push(PolicyViolation::new(
+                "breached",
+                "Password has appeared in a data breach",
+            ));
+        }
+
+        let username = username.trim().to_lowercase();
+        if username.chars().count() >= MIN_IDENTITY_MATCH_LENGTH && lowered.contains(&username) {
+            violations.push(PolicyViolation::new(
+                "contains_username",
+                "Password must not contain the username",
+            ));
+        }
+        let email = email.trim().to_lowercase();
+        let local_part = email.split('@').next().unwrap_or_default();
+        if local_part.chars().count() >= MIN_IDENTITY_MATCH_LENGTH && lowered.contains(local_part) {
+            violations.push(PolicyViolation::new(
+                "contains_email",
+                "Password must not contain the email address",
+            ));
+        }
+
+        violations
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+    Other,
+}
+
+impl CharClass {
+    fn of(c: char) -> Self {
+        if c.is_ascii_lowercase() {
+            CharClass::Lowercase
+        } else if c.is_ascii_uppercase() {
+            CharClass::Uppercase
+        } else if c.is_ascii_digit() {
+            CharClass::Digit
+        } else if c.is_ascii_punctuation() || c == ' ' {
+            CharClass::Symbol
+        } else {
+            CharClass::Other
+        }
+    }
+
+    fn pool_size(self) -> u32 {
+        match self {
+            CharClass::Lowercase | CharClass::Uppercase => 26,
+            CharClass::Digit => 10,
+            CharClass::Symbol => 33,
+            CharClass::Other => 100,
+        }
+    }
+
+    fn violation(self) -> PolicyViolation {
+        match self {
+            CharClass::Lowercase => {
+                PolicyViolation::new("lowercase", "Password must contain a lowercase letter")
+            }
+            CharClass::Uppercase => {
+                PolicyViolation::new("uppercase", "Password must contain an uppercase letter")
+            }
+            CharClass::Digit => PolicyViolation::new("digit", "Password must contain a digit"),
+            CharClass::Symbol | CharClass::Other => {
+                PolicyViolation::new("symbol", "Password must contain a symbol")
+            }
+        }
+    }
+}
+
+/// A brute-force estimate: length times the bits per character of the
+/// character classes in use. It overrates predictable passwords, which is
+/// what the banned-words list is for.
+fn entropy_bits(classes: &[CharClass], length: usize) -> f64 {
+    let pool: u32 = classes.iter().map(|class| class.pool_size()).sum();
+    if pool == 0 {
+        return 0.0;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let length = length as f64;
+    length * f64::from(pool).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(settings: &Settings) -> PasswordPolicy {
+        PasswordPolicy::from_settings(settings).unwrap()
+    }
+
+    async fn rules(policy: &PasswordPolicy, password: &str) -> Vec<&'static str> {
+        policy
+            .check(password, "alice", "alice@example.com")
+            .await
+            .into_iter()
+            .map(|violation| violation.rule)
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn default_policy_checks_length_only() {
+        let policy = policy(&Settings::default());
+        assert_eq!(rules(&policy, "short").await, ["min_length"]);
+        assert!(rules(&policy, "long enough").await.is_empty());
+        assert_eq!(rules(&policy, &"x".repeat(129)).await, ["max_length"]);
+    }
+
+    #[rocket::async_test]
+    async fn reports_every_missing_class() {
+        let policy = policy(&Settings {
+            password_require_lowercase: Some(true),
+            password_require_uppercase: Some(true),
+            password_require_digit: Some(true),
+            password_require_symbol: Some(true),
+            ..Settings::default()
+        });
+        assert_eq!(
+            rules(&policy, "ALLUPPERCASE").await,
+            ["lowercase", "digit", "symbol"]
+        );
+        assert!(rules(&policy, "Mixed-Case-1").await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_low_entropy() {
+        let policy = policy(&Settings {
+            password_min_entropy_bits: Some(50.0),
+            ..Settings::default()
+        });
+        // Ten lowercase letters are 47 bits, twelve are 56.
+        assert_eq!(rules(&policy, "abcdefghij").await, ["entropy"]);
+        assert!(rules(&policy, "abcdefghijkl").await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_banned_words_case_insensitively() {
+        let policy = policy(&Settings {
+            password_banned_words: Some(vec![" Password ".to_string(), "# note".to_string()]),
+            ..Settings::default()
+        });
+        assert_eq!(rules(&policy, "MyPassWord99").await, ["banned_word"]);
+        assert!(rules(&policy, "# note and more").await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_username_and_email_local_part() {
+        let policy = policy(&Settings::default());
+        assert_eq!(
+            rules(&policy, "Alice-is-here").await,
+            ["contains_username", "contains_email"]
+        );
+        let short = policy.check("xx-is-here", "xx", "xx@example.com").await;
+        assert!(short.is_empty());
+    }
+
+    #[test]
+    fn refuses_min_length_above_max_length() {
+        let settings = Settings {
+            password_min_length: Some(20),
+            password_max_length: Some(10),
+            ..Settings::default()
+        };
+        assert!(PasswordPolicy::from_settings(&settings).is_err());
+    }
+}
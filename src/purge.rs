@@ -0,0 +1,36 @@
+//! Background removal of deleted accounts. Deleting a user only schedules
+//! it; once the restore grace period lapses this job hard-deletes the
+//! account and scrubs the personal data held about it elsewhere: queued
+//! mail to any address it has held, and API keys it owns.
+
+use crate::database::Database;
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        select,
+        time::{sleep, Duration},
+    },
+};
+
+const PURGE_INTERVAL: Duration = Duration::from_mins(10);
+
+/// The fairing that starts the purge job once the server is up.
+pub fn worker(db: Database) -> AdHoc {
+    AdHoc::on_liftoff("Account purge", move |rocket| {
+        Box::pin(async move {
+            let mut shutdown = rocket.shutdown();
+            rocket::tokio::spawn(async move {
+                loop {
+                    // A failed pass is retried on the next tick.
+                    if let Err(err) = db.purge_deleted_users().await {
+                        rocket::error!("Failed to purge deleted users: {err}");
+                    }
+                    select! {
+                        () = sleep(PURGE_INTERVAL) => {}
+                        () = &mut shutdown => break,
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,517 @@
+//! Public response bodies. Internal models never derive `Serialize`; routes
+//! project them into one of these types instead.
+
+use crate::{
+    account::AccountStatus,
+    api_key::Scope,
+    email_queue::EmailStatus,
+    models::{ApiKeyRecord, PreviousUsername, QueuedEmail, StatusChange, User},
+};
+use serde::Serialize;
+use surrealdb::sql::Datetime;
+
+#[derive(Debug, Serialize)]
+pub struct UserResponse {
+    pub email: String,
+    pub username: String,
+    pub logged_in: bool,
+    pub locale: Option<String>,
+    pub verified: bool,
+    pub status: AccountStatus,
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<String>,
+    /// When a pending deletion becomes permanent, if one is scheduled.
+    pub purge_after: Option<String>,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        UserResponse {
+            email: user.email,
+            username: user.username,
+            logged_in: user.logged_in,
+            locale: user.locale,
+            verified: user.verified,
+            status: user.status,
+            status_reason: user.status_reason,
+            status_changed_at: user.status_changed_at.map(|date| date.to_raw()),
+            purge_after: user.purge_after.map(|purge_after| purge_after.to_raw()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreviousUsernameResponse {
+    pub username: String,
+    pub changed_at: String,
+}
+
+impl From<PreviousUsername> for PreviousUsernameResponse {
+    fn from(previous: PreviousUsername) -> Self {
+        PreviousUsernameResponse {
+            username: previous.username,
+            changed_at: previous.changed_at.to_raw(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusChangeResponse {
+    pub from: Option<AccountStatus>,
+    pub to: AccountStatus,
+    pub reason: String,
+    pub at: String,
+}
+
+impl From<StatusChange> for StatusChangeResponse {
+    fn from(change: StatusChange) -> Self {
+        StatusChangeResponse {
+            from: change.from,
+            to: change.to,
+            reason: change.reason,
+            at: change.at.to_raw(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginSuccess {
+    pub email: String,
+    pub username: String,
+}
+
+impl From<User> for LoginSuccess {
+    fn from(user: User) -> Self {
+        LoginSuccess {
+            email: user.email,
+            username: user.username,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyResponse {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+}
+
+impl From<ApiKeyRecord> for ApiKeyResponse {
+    fn from(record: ApiKeyRecord) -> Self {
+        ApiKeyResponse {
+            id: record.key_id,
+            name: record.name,
+            owner: record.owner,
+            scopes: record.scopes,
+            created_at: record.created_at.to_raw(),
+            expires_at: record.expires_at.map(|date| date.to_raw()),
+            last_used_at: record.last_used_at.map(|date| date.to_raw()),
+            revoked_at: record.revoked_at.map(|date| date.to_raw()),
+        }
+    }
+}
+
+/// Returned once when a key is created; `key` is never retrievable again.
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub details: ApiKeyResponse,
+}
+
+/// A freshly issued recovery code. When the server cannot send mail the
+/// code is returned once for the caller to deliver to the user. Both fields
+/// are left out when no code was issued.
+#[derive(Debug, Default, Serialize)]
+pub struct RecoveryIssued {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+/// A freshly issued email verification token, returned only when the
+/// server cannot send mail.
+#[derive(Debug, Serialize)]
+pub struct VerificationIssued {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub expires_at: String,
+}
+
+/// A pending email change. The tokens are returned only when the server
+/// cannot send mail.
+#[derive(Debug, Serialize)]
+pub struct EmailChangeIssued {
+    pub new_email: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_token: Option<String>,
+    pub expires_at: String,
+}
+
+/// A password policy rule a new password breaks, with the `rule` name from
+/// the `password_*` settings so clients can localize the message.
+#[derive(Debug, Serialize)]
+pub struct PolicyViolation {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl PolicyViolation {
+    pub fn new(rule: &'static str, message: impl Into<String>) -> Self {
+        PolicyViolation {
+            rule,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportRowError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub errors: Vec<ImportRowError>,
+}
+
+/// One user in a bulk export. Password hashes are only included when the
+/// export asks for them, for migrating to another system.
+#[derive(Debug, Serialize)]
+pub struct ExportedUser {
+    pub email: String,
+    pub username: String,
+    pub logged_in: bool,
+    pub status: AccountStatus,
+    pub created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+}
+
+impl ExportedUser {
+    pub fn new(user: User, include_password_hash: bool) -> Self {
+        ExportedUser {
+            email: user.email,
+            username: user.username,
+            logged_in: user.logged_in,
+            status: user.status,
+            created_at: user.created_at.map(|created_at| created_at.to_raw()),
+            password_hash: include_password_hash.then_some(user.password),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueuedEmailResponse {
+    pub id: String,
+    pub recipient: String,
+    pub subject: String,
+    pub status: EmailStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: String,
+    pub created_at: String,
+    pub sent_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+impl From<QueuedEmail> for QueuedEmailResponse {
+    fn from(email: QueuedEmail) -> Self {
+        QueuedEmailResponse {
+            id: email.id.id.to_raw(),
+            recipient: email.recipient,
+            subject: email.subject,
+            status: email.status,
+            attempts: email.attempts,
+            last_error: email.last_error,
+            next_attempt_at: email.next_attempt_at.to_raw(),
+            created_at: email.created_at.to_raw(),
+            sent_at: email.sent_at.map(|sent_at| sent_at.to_raw()),
+            expires_at: email.expires_at.map(|expires_at| expires_at.to_raw()),
+        }
+    }
+}
+
+/// Kinds of personal data this service does not keep, listed in every
+/// export so their absence reads as deliberate rather than an omission.
+pub const NOT_RECORDED: [&str; 3] = ["login_history", "audit_log", "consents"];
+
+/// Everything held about one user, for data subject access requests.
+/// Every stored field is included except password hashes, recovery codes
+/// and token digests.
+#[derive(Debug, Serialize)]
+pub struct UserDataExport {
+    pub generated_at: String,
+    pub not_recorded: [&'static str; 3],
+    pub profile: ExportedProfile,
+    pub logged_in: bool,
+    pub pending: PendingRequests,
+    pub emails: Vec<QueuedEmailResponse>,
+    pub api_keys: Vec<ApiKeyResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedProfile {
+    pub email: String,
+    pub username: String,
+    pub locale: Option<String>,
+    pub verified: bool,
+    pub created_at: Option<String>,
+    pub previous_usernames: Vec<PreviousUsernameResponse>,
+    pub previous_emails: Vec<String>,
+    pub status: AccountStatus,
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<String>,
+    pub status_history: Vec<StatusChangeResponse>,
+    pub failed_logins: u32,
+    pub locked_until: Option<String>,
+    pub deleted_at: Option<String>,
+    pub purge_after: Option<String>,
+}
+
+/// When each outstanding code or token expires, without the code itself.
+#[derive(Debug, Serialize)]
+pub struct PendingRequests {
+    pub recovery_expires_at: Option<String>,
+    pub recovery_attempts: Option<u32>,
+    pub verification_expires_at: Option<String>,
+    pub verification_sent_at: Option<String>,
+    pub email_change: Option<PendingEmailChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingEmailChange {
+    pub new_email: String,
+    pub expires_at: String,
+}
+
+impl UserDataExport {
+    pub fn new(user: User, emails: Vec<QueuedEmail>, api_keys: Vec<ApiKeyRecord>) -> Self {
+        UserDataExport {
+            generated_at: Datetime::default().to_raw(),
+            not_recorded: NOT_RECORDED,
+            profile: ExportedProfile {
+                email: user.email,
+                username: user.username,
+                locale: user.locale,
+                verified: user.verified,
+                created_at: user.created_at.map(|date| date.to_raw()),
+                previous_usernames: user
+                    .previous_usernames
+                    .into_iter()
+                    .map(PreviousUsernameResponse::from)
+                    .collect(),
+                previous_emails: user.previous_emails,
+                status: user.status,
+                status_reason: user.status_reason,
+                status_changed_at: user.status_changed_at.map(|date| date.to_raw()),
+                status_history: user
+                    .status_history
+                    .into_iter()
+                    .map(StatusChangeResponse::from)
+                    .collect(),
+                failed_logins: user.failed_logins,
+                locked_until: user.locked_until.map(|date| date.to_raw()),
+                deleted_at: user.deleted_at.map(|date| date.to_raw()),
+                purge_after: user.purge_after.map(|date| date.to_raw()),
+            },
+            logged_in: user.logged_in,
+            pending: PendingRequests {
+                recovery_expires_at: user
+                    .recovery
+                    .as_ref()
+                    .map(|recovery| recovery.expires_at.to_raw()),
+                recovery_attempts: user.recovery.map(|recovery| recovery.attempts),
+                verification_expires_at: user
+                    .verification
+                    .as_ref()
+                    .map(|verification| verification.expires_at.to_raw()),
+                verification_sent_at: user
+                    .verification
+                    .and_then(|verification| verification.sent_at)
+                    .map(|date| date.to_raw()),
+                email_change: user.email_change.map(|change| PendingEmailChange {
+                    new_email: change.new_email,
+                    expires_at: change.expires_at.to_raw(),
+                }),
+            },
+            emails: emails.into_iter().map(QueuedEmailResponse::from).collect(),
+            api_keys: api_keys.into_iter().map(ApiKeyResponse::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use surrealdb::sql::Thing;
+
+    const SECRET_FIELDS: [&str; 7] = [
+        "password",
+        "password_history",
+        "recovery",
+        "key_hash",
+        "token_hash",
+        "confirm_hash",
+        "cancel_hash",
+    ];
+
+    /// A user record as stored, with every secret the database can hold.
+    fn stored_user() -> User {
+        serde_json::from_value(json!({
+            "email": "user@example.com",
+            "username": "user",
+            "password": "SECRET-password",
+            "password_history": ["SECRET-old-password"],
+            "logged_in": true,
+            "recovery": {
+                "code_hash": "SECRET-code",
+                "expires_at": "2030-01-01T00:00:00Z",
+                "attempts": 1,
+            },
+            "verification": {
+                "token_hash": "SECRET-token",
+                "expires_at": "2030-01-01T00:00:00Z",
+                "sent_at": "2029-12-31T00:00:00Z",
+            },
+            "email_change": {
+                "new_email": "new@example.com",
+                "confirm_hash": "SECRET-confirm",
+                "cancel_hash": "SECRET-cancel",
+                "expires_at": "2030-01-01T00:00:00Z",
+            },
+            "status": "active",
+        }))
+        .unwrap()
+    }
+
+    /// Every key in `value`, at any depth.
+    fn keys(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    found.push(key.clone());
+                    keys(value, found);
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|value| keys(value, found)),
+            _ => {}
+        }
+    }
+
+    fn stored_api_key() -> ApiKeyRecord {
+        serde_json::from_value(json!({
+            "key_id": "key",
+            "name": "CI",
+            "owner": "user",
+            "key_hash": "SECRET-key",
+            "scopes": ["users:read"],
+            "created_at": "2029-01-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    fn assert_no_secrets(response: impl Serialize) {
+        let value = serde_json::to_value(response).unwrap();
+        let mut found = Vec::new();
+        keys(&value, &mut found);
+        for field in SECRET_FIELDS {
+            assert!(!found.iter().any(|key| key == field), "{field} in {value}");
+        }
+        assert!(
+            !value.to_string().contains("SECRET"),
+            "secret value in {value}"
+        );
+    }
+
+    #[test]
+    fn user_response_has_no_secrets() {
+        assert_no_secrets(UserResponse::from(stored_user()));
+    }
+
+    #[test]
+    fn login_success_has_no_secrets() {
+        assert_no_secrets(LoginSuccess::from(stored_user()));
+    }
+
+    #[test]
+    fn exported_user_has_no_secrets_unless_asked() {
+        assert_no_secrets(ExportedUser::new(stored_user(), false));
+        let with_hash = ExportedUser::new(stored_user(), true);
+        assert_eq!(with_hash.password_hash.as_deref(), Some("SECRET-password"));
+    }
+
+    #[test]
+    fn api_key_response_has_no_secrets() {
+        assert_no_secrets(ApiKeyResponse::from(stored_api_key()));
+    }
+
+    #[test]
+    fn user_data_export_has_no_secrets() {
+        let email = QueuedEmail {
+            id: Thing::from(("EmailQueue", "1")),
+            recipient: "user@example.com".to_string(),
+            subject: "Reset your password".to_string(),
+            text: Some("SECRET-body".to_string()),
+            html: Some("SECRET-body".to_string()),
+            status: EmailStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: Datetime::default(),
+            created_at: Datetime::default(),
+            sent_at: None,
+            expires_at: Some(Datetime::default()),
+        };
+        assert_no_secrets(UserDataExport::new(
+            stored_user(),
+            vec![email],
+            vec![stored_api_key()],
+        ));
+    }
+
+    #[test]
+    fn user_data_export_has_every_stored_field() {
+        let stored =
+            serde_json::to_value(UserDataExport::new(stored_user(), vec![], vec![])).unwrap();
+        let mut found = Vec::new();
+        keys(&stored, &mut found);
+        for field in [
+            "email",
+            "username",
+            "locale",
+            "verified",
+            "created_at",
+            "previous_usernames",
+            "previous_emails",
+            "status",
+            "status_reason",
+            "status_changed_at",
+            "status_history",
+            "failed_logins",
+            "locked_until",
+            "deleted_at",
+            "purge_after",
+            "logged_in",
+            "recovery_attempts",
+            "verification_sent_at",
+            "email_change",
+        ] {
+            assert!(found.iter().any(|key| key == field), "{field} missing");
+        }
+        assert_eq!(
+            stored["not_recorded"],
+            json!(["login_history", "audit_log", "consents"])
+        );
+    }
+}
@@ -1,84 +1,104 @@
 use crate::{
+    api_key::{authorize, ApiKey, KeyVerifier, Scope},
     database::Database,
-    hash::verify_password,
-    models::{EmailLogin, LoginSuccess, SignUp, User, UsernameLogin},
+    error::ApiError,
+    mailer::Mailer,
+    models::{EmailLogin, SignUp, UsernameLogin},
+    responses::{LoginSuccess, UserResponse},
+    verification::issue_verification,
 };
 use core::future::Future;
 use rocket::serde::json::Json;
 use rocket::State;
-use surrealdb::{error::Db::Thrown, Error, Error::Db};
+use surrealdb::{error::Db::Thrown, Error::Db};
 
-async fn verify_api_key<T, F, U>(
-    key: String,
-    api_key: &State<String>,
+pub async fn verify_api_key<T, F, U, E>(
+    key: ApiKey,
+    scope: Scope,
+    db: &Database,
+    verifier: &State<KeyVerifier>,
     action: T,
-) -> Result<U, Json<Error>>
+) -> Result<U, ApiError>
 where
     T: FnOnce() -> F,
-    F: Future<Output = Result<U, Error>>,
+    F: Future<Output = Result<U, E>>,
+    ApiError: From<E>,
 {
-    let verify_key_result = verify_password(key, api_key.to_string());
-    match verify_key_result {
-        Ok(verify_key) => {
-            if verify_key {
-                action().await.map_err(Json)
-            } else {
-                Err(Json(Db(Thrown("Api key is invalid".to_string()))))
-            }
-        }
-        Err(_) => Err(Json(Db(Thrown("Api key is required".to_string())))),
+    authorize(key, scope, db, verifier).await?;
+    action().await.map_err(ApiError::from)
+}
+
+/// Rejects a new password that breaks the configured policy, listing every
+/// violated rule. Call this wherever a password is set.
+pub async fn enforce_password_policy(
+    db: &Database,
+    password: &str,
+    username: &str,
+    email: &str,
+) -> Result<(), ApiError> {
+    let violations = db.password_policy.check(password, username, email).await;
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::password_policy(violations))
     }
 }
 
 #[post("/signup", data = "<user>")]
 pub async fn signup(
+    key: ApiKey,
     user: Json<SignUp>,
     db: &State<Database>,
-    api_key: &State<String>,
-) -> Result<Json<User>, Json<Error>> {
-    verify_api_key(user.api_key.clone(), api_key, || async {
+    mailer: &State<Mailer>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let key = key.or_legacy(user.api_key.clone());
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        enforce_password_policy(db, &user.password, &user.username, &user.email).await?;
         let created_user = db.signup(user.into_inner()).await;
 
         match created_user {
             Ok(result) => match result {
-                Some(user) => Ok(Json(user)),
-                None => Err(Db(Thrown("An error occured".to_string()))),
+                Some(user) => {
+                    if mailer.is_enabled() {
+                        issue_verification(db, mailer, verifier, &user).await?;
+                    }
+                    Ok(Json(UserResponse::from(user)))
+                }
+                None => Err(ApiError::from(Db(Thrown("An error occured".to_string())))),
             },
-            Err(err) => Err(err),
+            Err(err) => Err(ApiError::from(err)),
         }
     })
     .await
 }
 
-#[get("/get_user/<username>?<key>")]
+#[get("/get_user/<username>")]
 pub async fn get_user(
     username: String,
-    key: String,
+    key: ApiKey,
     db: &State<Database>,
-    api_key: &State<String>,
-) -> Result<Json<User>, Json<Error>> {
-    verify_api_key(key, api_key, || async {
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersRead, db, verifier, || async {
         let user_result = db.get_user(username).await;
         match user_result {
-            Ok(Some(user)) => Ok(Json(user)),
-            Ok(None) => {
-                let result_string = "User not found".to_string();
-                Err(Db(Thrown(result_string)))
-            }
-            Err(err) => Err(err),
+            Ok(Some(user)) => Ok(Json(UserResponse::from(user))),
+            Ok(None) => Err(ApiError::not_found("User not found")),
+            Err(err) => Err(err.into()),
         }
     })
     .await
 }
 
-#[get("/delete_user/<username>?<key>")]
+#[get("/delete_user/<username>")]
 pub async fn delete_user(
     username: String,
-    key: String,
+    key: ApiKey,
     db: &State<Database>,
-    api_key: &State<String>,
-) -> Result<String, Json<Error>> {
-    verify_api_key(key, api_key, || async {
+    verifier: &State<KeyVerifier>,
+) -> Result<String, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
         let delete_result = db.delete_user(username.clone()).await;
 
         match delete_result {
@@ -91,11 +111,13 @@ pub async fn delete_user(
 
 #[post("/email_login", data = "<credentials>")]
 pub async fn email_login(
+    key: ApiKey,
     credentials: Json<EmailLogin>,
     db: &State<Database>,
-    api_key: &State<String>,
-) -> Result<Json<LoginSuccess>, Json<Error>> {
-    verify_api_key(credentials.api_key.clone(), api_key, || async {
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<LoginSuccess>, ApiError> {
+    let key = key.or_legacy(credentials.api_key.clone());
+    verify_api_key(key, Scope::SessionsWrite, db, verifier, || async {
         let login_result = db.email_login(credentials.into_inner()).await;
         match login_result {
             Ok(login_success) => Ok(Json(login_success)),
@@ -105,14 +127,14 @@ pub async fn email_login(
     .await
 }
 
-#[get("/signout/<username>?<key>")]
+#[get("/signout/<username>")]
 pub async fn signout(
     username: String,
-    key: String,
+    key: ApiKey,
     db: &State<Database>,
-    api_key: &State<String>,
-) -> Result<String, Json<Error>> {
-    verify_api_key(key, api_key, || async {
+    verifier: &State<KeyVerifier>,
+) -> Result<String, ApiError> {
+    verify_api_key(key, Scope::SessionsWrite, db, verifier, || async {
         let signout_result = db.signout(username).await;
         match signout_result {
             Ok(success) => Ok(success),
@@ -124,11 +146,13 @@ pub async fn signout(
 
 #[post("/username_login", data = "<credentials>")]
 pub async fn username_login(
+    key: ApiKey,
     credentials: Json<UsernameLogin>,
     db: &State<Database>,
-    api_key: &State<String>,
-) -> Result<Json<LoginSuccess>, Json<Error>> {
-    verify_api_key(credentials.api_key.clone(), api_key, || async {
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<LoginSuccess>, ApiError> {
+    let key = key.or_legacy(credentials.api_key.clone());
+    verify_api_key(key, Scope::SessionsWrite, db, verifier, || async {
         let login_result = db.username_login(credentials.into_inner()).await;
         match login_result {
             Ok(login_success) => Ok(Json(login_success)),
@@ -138,31 +162,6 @@ pub async fn username_login(
     .await
 }
 
-// #[get("/account_recovery/<username>?<key>")]
-// pub async fn account_recovery(
-//     username: String,
-//     key: String,
-//     api_key: &State<String>,
-//     db: &State<Database>,
-// ) -> Result<String, Json<Error>> {
-//     verify_api_key(key, api_key, || async {
-//         match db.check_code(username.clone()).await {
-//             Ok(user) => {
-//                 match send_code_mail(
-//                     user.recovery_code.unwrap().to_string(),
-//                     username,
-//                     user.email,
-//                 ) {
-//                     Ok(_) => Ok("Email with authentication code sent to user's email".to_string()),
-//                     Err(err) => Err(Error::Db(Thrown(err.to_string()))),
-//                 }
-//             }
-//             Err(e) => Err(e),
-//         }
-//     })
-//     .await
-// }
-
 #[get("/")]
 pub fn root() -> &'static str {
     "Welcome to the Rust Auth Server created by PyDev19"
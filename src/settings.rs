@@ -1,4 +1,5 @@
-use crate::hash::{generate_salt, hash_password, verify_password};
+use crate::hash::{generate_salt, hash_password, to_hex, verify_password};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::{self, Write};
@@ -10,20 +11,91 @@ pub enum DatabaseType {
     Local,
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum Argon2Variant {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum MailTransportKind {
+    Smtp,
+    Sendmail,
+    File,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub enum SmtpTls {
+    StartTls,
+    Tls,
+    Plain,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct Settings {
     pub root_user: Option<String>,
     pub root_password: Option<String>,
     pub api_key: Option<String>,
+    pub api_key_secret: Option<String>,
     pub database_type: Option<DatabaseType>,
     pub database_endpoint: Option<String>,
+    pub legacy_api_key_locations: Option<bool>,
+    pub hash_workers: Option<usize>,
+    pub hash_queue_timeout_ms: Option<u64>,
+    pub argon2_variant: Option<Argon2Variant>,
+    pub argon2_memory_cost: Option<u32>,
+    pub argon2_iterations: Option<u32>,
+    pub argon2_parallelism: Option<u32>,
+    pub pepper_file: Option<String>,
+    pub pepper_version: Option<String>,
+    pub password_min_length: Option<usize>,
+    pub password_max_length: Option<usize>,
+    pub password_require_lowercase: Option<bool>,
+    pub password_require_uppercase: Option<bool>,
+    pub password_require_digit: Option<bool>,
+    pub password_require_symbol: Option<bool>,
+    pub password_min_entropy_bits: Option<f64>,
+    pub password_banned_words: Option<Vec<String>>,
+    pub password_banned_words_file: Option<String>,
+    pub breached_passwords_file: Option<String>,
+    pub breached_passwords_filter: Option<String>,
+    pub password_history_size: Option<usize>,
+    pub recovery_code_ttl_minutes: Option<u32>,
+    pub recovery_max_attempts: Option<u32>,
+    pub recovery_resend_seconds: Option<u64>,
+    pub mail_transport: Option<MailTransportKind>,
+    pub mail_from: Option<String>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_tls: Option<SmtpTls>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub sendmail_command: Option<String>,
+    pub mail_file_dir: Option<String>,
+    pub mail_template_dir: Option<String>,
+    pub mail_default_locale: Option<String>,
+    pub email_max_attempts: Option<u32>,
+    pub email_retry_base_seconds: Option<u64>,
+    pub email_verification_ttl_minutes: Option<u32>,
+    pub email_verification_resend_seconds: Option<u64>,
+    pub require_verified_email: Option<bool>,
+    pub username_reservation_days: Option<u32>,
+    pub deletion_grace_days: Option<u32>,
+    pub login_lockout_threshold: Option<u32>,
+    pub login_lockout_minutes: Option<u32>,
 }
 
-pub fn check_json() -> (Settings, String) {
-    let settings: Settings = match fs::read_to_string("settings.json") {
+/// Reads `settings.json` without prompting for anything missing.
+pub fn read_settings() -> Settings {
+    match fs::read_to_string("settings.json") {
         Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
         Err(_) => Settings::default(),
-    };
+    }
+}
+
+pub fn check_json() -> (Settings, String) {
+    let settings = read_settings();
 
     let mut updated_settings = settings.clone();
 
@@ -33,6 +105,7 @@ pub fn check_json() -> (Settings, String) {
         "database type",
         "database endpoint",
         "api key",
+        "api key secret",
         "email",
     ];
 
@@ -53,6 +126,10 @@ pub fn check_json() -> (Settings, String) {
                 updated_settings.api_key =
                     hash_password(key.unwrap_or_default(), salt.clone()).ok();
             }
+            "api key secret" if updated_settings.api_key_secret.is_none() => {
+                let secret: [u8; 32] = rand::thread_rng().gen();
+                updated_settings.api_key_secret = Some(to_hex(&secret));
+            }
             "database type" if updated_settings.database_type.is_none() => {
                 let db_type = prompt_user("Set database type (remote/local): ");
                 match db_type {
@@ -0,0 +1,340 @@
+//! Transactional email templates.
+//!
+//! A template is a set of files named after it in a per-locale directory
+//! under `mail_template_dir`: `<locale>/<name>.subject`, `<name>.txt` and an
+//! optional `<name>.html`. `{{variable}}` placeholders are replaced with the
+//! values passed in, HTML-escaped in the HTML part. English templates are
+//! built in, so a template directory only needs the files it overrides.
+//! The directory is read once at startup.
+
+use crate::settings::Settings;
+use std::{collections::HashMap, fs, io, path::Path, sync::Arc};
+
+const BUILT_IN_LOCALE: &str = "en";
+const BUILT_IN: [(&str, &str, &str, &str); 5] = [
+    (
+        "recovery",
+        include_str!("../templates/en/recovery.subject"),
+        include_str!("../templates/en/recovery.txt"),
+        include_str!("../templates/en/recovery.html"),
+    ),
+    (
+        "verification",
+        include_str!("../templates/en/verification.subject"),
+        include_str!("../templates/en/verification.txt"),
+        include_str!("../templates/en/verification.html"),
+    ),
+    (
+        "security_notice",
+        include_str!("../templates/en/security_notice.subject"),
+        include_str!("../templates/en/security_notice.txt"),
+        include_str!("../templates/en/security_notice.html"),
+    ),
+    (
+        "email_change",
+        include_str!("../templates/en/email_change.subject"),
+        include_str!("../templates/en/email_change.txt"),
+        include_str!("../templates/en/email_change.html"),
+    ),
+    (
+        "email_change_notice",
+        include_str!("../templates/en/email_change_notice.subject"),
+        include_str!("../templates/en/email_change_notice.txt"),
+        include_str!("../templates/en/email_change_notice.html"),
+    ),
+];
+
+/// Values used by `rustauth preview-email`, covering every variable the
+/// built-in templates use.
+pub const SAMPLE_VARIABLES: [(&str, &str); 5] = [
+    ("username", "sample_user"),
+    ("code", "123456"),
+    ("expires_in_minutes", "15"),
+    ("event", "Your password was changed."),
+    ("new_email", "new_address@example.com"),
+];
+
+/// An email, or a template for one before its variables are substituted.
+#[derive(Clone)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+/// Templates from `mail_template_dir`, keyed by locale and then name.
+type Loaded = HashMap<String, HashMap<String, RenderedEmail>>;
+
+#[derive(Clone)]
+pub struct EmailTemplates {
+    loaded: Arc<Loaded>,
+    built_in: Arc<HashMap<String, RenderedEmail>>,
+    default_locale: String,
+}
+
+impl EmailTemplates {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let loaded = match &settings.mail_template_dir {
+            Some(dir) => load_dir(Path::new(dir))
+                .map_err(|err| format!("Invalid mail_template_dir {dir}: {err}"))?,
+            None => Loaded::new(),
+        };
+        let built_in = BUILT_IN
+            .iter()
+            .map(|(name, subject, text, html)| {
+                let template = RenderedEmail {
+                    subject: (*subject).to_string(),
+                    text: (*text).to_string(),
+                    html: Some((*html).to_string()),
+                };
+                ((*name).to_string(), template)
+            })
+            .collect();
+        Ok(EmailTemplates {
+            loaded: Arc::new(loaded),
+            built_in: Arc::new(built_in),
+            default_locale: settings
+                .mail_default_locale
+                .clone()
+                .unwrap_or_else(|| BUILT_IN_LOCALE.to_string()),
+        })
+    }
+
+    /// Renders `name` in the most specific available locale: `locale` as
+    /// given (`pt-BR`), then its language (`pt`), then the default locale,
+    /// then the built-in English template.
+    pub fn render(
+        &self,
+        name: &str,
+        locale: Option<&str>,
+        variables: &[(&str, &str)],
+    ) -> Result<RenderedEmail, String> {
+        let template = self
+            .load(name, locale)
+            .ok_or_else(|| format!("Unknown email template {name}"))?;
+        Ok(RenderedEmail {
+            subject: substitute(template.subject.trim(), variables, false)?,
+            text: substitute(&template.text, variables, false)?,
+            html: template
+                .html
+                .as_deref()
+                .map(|html| substitute(html, variables, true))
+                .transpose()?,
+        })
+    }
+
+    fn load(&self, name: &str, locale: Option<&str>) -> Option<&RenderedEmail> {
+        let mut locales: Vec<&str> = Vec::new();
+        if let Some(locale) = locale.filter(|locale| is_valid_locale(locale)) {
+            locales.push(locale);
+            locales.extend(locale.split('-').next());
+        }
+        locales.push(&self.default_locale);
+        locales.dedup();
+
+        locales
+            .iter()
+            .find_map(|locale| self.loaded.get(*locale)?.get(name))
+            .or_else(|| self.built_in.get(name))
+    }
+}
+
+/// Reads every template under `dir`: one subdirectory per locale holding
+/// `<name>.subject`, `<name>.txt` and optionally `<name>.html`. Templates
+/// missing a subject or text part are skipped.
+fn load_dir(dir: &Path) -> io::Result<Loaded> {
+    let mut loaded = Loaded::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let locale = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_dir() || !is_valid_locale(&locale) {
+            continue;
+        }
+
+        let mut parts: HashMap<String, HashMap<String, String>> = HashMap::new();
+        for file in fs::read_dir(entry.path())? {
+            let path = file?.path();
+            let (Some(name), Some(extension)) = (
+                path.file_stem().and_then(|stem| stem.to_str()),
+                path.extension().and_then(|extension| extension.to_str()),
+            ) else {
+                continue;
+            };
+            if matches!(extension, "subject" | "txt" | "html") {
+                parts
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(extension.to_string(), fs::read_to_string(&path)?);
+            }
+        }
+
+        let templates = parts
+            .into_iter()
+            .filter_map(|(name, mut parts)| {
+                let template = RenderedEmail {
+                    subject: parts.remove("subject")?,
+                    text: parts.remove("txt")?,
+                    html: parts.remove("html"),
+                };
+                Some((name, template))
+            })
+            .collect();
+        loaded.insert(locale, templates);
+    }
+    Ok(loaded)
+}
+
+/// Whether `locale` looks like a BCP 47 tag such as `en` or `pt-BR`.
+pub fn is_valid_locale(locale: &str) -> bool {
+    !locale.is_empty()
+        && locale.len() <= 35
+        && locale.split('-').all(|part| {
+            !part.is_empty() && part.len() <= 8 && part.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+fn substitute(template: &str, variables: &[(&str, &str)], escape: bool) -> Result<String, String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "Unclosed {{ in email template".to_string())?;
+        let key = after[..end].trim();
+        let value = variables
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| format!("Unknown email template variable {key}"))?;
+        if escape {
+            output.push_str(&escape_html(value));
+        } else {
+            output.push_str(value);
+        }
+        rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .chars()
+        .fold(String::with_capacity(value.len()), |mut escaped, c| {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#39;"),
+                _ => escaped.push(c),
+            }
+            escaped
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, path::PathBuf};
+
+    const VARIABLES: [(&str, &str); 3] = [
+        ("username", "<b>ann</b>"),
+        ("code", "123456"),
+        ("expires_in_minutes", "15"),
+    ];
+
+    fn template_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustauth-templates-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn templates(dir: Option<&Path>, default_locale: Option<&str>) -> EmailTemplates {
+        EmailTemplates::from_settings(&Settings {
+            mail_template_dir: dir.map(|dir| dir.to_string_lossy().into_owned()),
+            mail_default_locale: default_locale.map(str::to_string),
+            ..Settings::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn falls_back_to_built_in_english() {
+        let email = templates(None, None)
+            .render("recovery", Some("fr"), &VARIABLES)
+            .unwrap();
+        assert_eq!(email.subject, "Your account recovery code");
+        assert!(email.text.contains("123456"));
+    }
+
+    #[test]
+    fn prefers_locale_then_language_then_default() {
+        let dir = template_dir(
+            "fallback",
+            &[
+                ("pt-BR/recovery.subject", "pt-BR"),
+                ("pt-BR/recovery.txt", "{{code}}"),
+                ("pt/recovery.subject", "pt"),
+                ("pt/recovery.txt", "{{code}}"),
+                ("de/recovery.subject", "de"),
+                ("de/recovery.txt", "{{code}}"),
+                // Without a text part this template is ignored.
+                ("fr/recovery.subject", "fr"),
+            ],
+        );
+        let templates = templates(Some(&dir), Some("de"));
+        let subject = |locale| {
+            templates
+                .render("recovery", locale, &VARIABLES)
+                .unwrap()
+                .subject
+        };
+        assert_eq!(subject(Some("pt-BR")), "pt-BR");
+        assert_eq!(subject(Some("pt-PT")), "pt");
+        assert_eq!(subject(Some("fr")), "de");
+        assert_eq!(subject(None), "de");
+        assert_eq!(
+            templates
+                .render("verification", Some("pt"), &VARIABLES)
+                .unwrap()
+                .subject,
+            "Confirm your email address"
+        );
+    }
+
+    #[test]
+    fn escapes_variables_only_in_html() {
+        let email = templates(None, None)
+            .render("recovery", None, &VARIABLES)
+            .unwrap();
+        assert!(email.text.contains("<b>ann</b>"));
+        let html = email.html.unwrap();
+        assert!(html.contains("&lt;b&gt;ann&lt;/b&gt;"));
+        assert!(!html.contains("<b>ann"));
+    }
+
+    #[test]
+    fn rejects_unknown_templates_and_variables() {
+        let templates = templates(None, None);
+        assert!(templates.render("missing", None, &VARIABLES).is_err());
+        assert!(templates.render("recovery", None, &VARIABLES[..1]).is_err());
+        assert!(substitute("{{code", &VARIABLES, false).is_err());
+    }
+
+    #[test]
+    fn missing_template_dir_is_a_settings_error() {
+        let settings = Settings {
+            mail_template_dir: Some("/nonexistent/templates".to_string()),
+            ..Settings::default()
+        };
+        assert!(EmailTemplates::from_settings(&settings).is_err());
+    }
+}
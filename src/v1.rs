@@ -0,0 +1,935 @@
+use crate::{
+    account::AccountStatus,
+    api_key::{ApiKey, KeyVerifier, Scope},
+    database::Database,
+    email_queue::{queue_email, queue_email_to, EmailStatus},
+    error::ApiError,
+    export::{self, ExportFilter, ExportFormat},
+    import::{self, ImportFormat},
+    mailer::Mailer,
+    models::{
+        EmailChangeRequest, EmailChangeToken, EmailLogin, NewApiKey, NewSession, PasswordChange,
+        PasswordReset, RecoveryRequest, SignUp, StatusChangeRequest, User, UserUpdate,
+        UsernameChange, UsernameLogin, VerificationResend, VerifyEmail,
+    },
+    responses::{
+        ApiKeyResponse, CreatedApiKey, EmailChangeIssued, ExportedUser, ImportReport, LoginSuccess,
+        PreviousUsernameResponse, QueuedEmailResponse, RecoveryIssued, StatusChangeResponse,
+        UserDataExport, UserResponse, VerificationIssued,
+    },
+    routes::{enforce_password_policy, verify_api_key},
+    verification::{
+        email_change_input, issue_verification, random_token, recovery_code_input,
+        verification_token_input,
+    },
+};
+use rand::Rng;
+use rocket::{
+    data::{Data, Limits, ToByteUnit},
+    http::{ContentType, Status},
+    response::{
+        status::{Accepted, Created, NoContent},
+        stream::TextStream,
+    },
+    serde::json::Json,
+    State,
+};
+use surrealdb::sql::Datetime;
+
+async fn require_user(db: &Database, username: String) -> Result<User, ApiError> {
+    db.get_user(username)
+        .await?
+        .ok_or_else(|| ApiError::not_found("User not found"))
+}
+
+async fn find_account(
+    db: &Database,
+    email: Option<String>,
+    username: Option<String>,
+) -> Result<Option<User>, ApiError> {
+    match (email, username) {
+        (Some(email), _) => Ok(db.get_user_by_email(email).await?),
+        (None, Some(username)) => Ok(db.get_user(username).await?),
+        (None, None) => Err(ApiError::new(
+            Status::BadRequest,
+            "Email or username is required",
+        )),
+    }
+}
+
+/// Creates an unverified user and emails them a verification token. Without
+/// mail configured, `POST /verify_email/resend` returns a token instead.
+#[post("/users", data = "<user>")]
+pub async fn create_user(
+    key: ApiKey,
+    user: Json<SignUp>,
+    db: &State<Database>,
+    mailer: &State<Mailer>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Created<Json<UserResponse>>, ApiError> {
+    let key = key.or_legacy(user.api_key.clone());
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        enforce_password_policy(db, &user.password, &user.username, &user.email).await?;
+        match db.signup(user.into_inner()).await? {
+            Some(user) => {
+                if mailer.is_enabled() {
+                    issue_verification(db, mailer, verifier, &user).await?;
+                }
+                Ok(Created::new(format!("/v1/users/{}", user.username))
+                    .body(Json(UserResponse::from(user))))
+            }
+            None => Err(ApiError::new(
+                Status::InternalServerError,
+                "An error occured",
+            )),
+        }
+    })
+    .await
+}
+
+/// Creates users from a CSV or JSON Lines body. Each row carries an email,
+/// username and either a plaintext `password` or an existing `password_hash`.
+/// Rows that fail validation are skipped and listed in the report.
+#[post("/users/import", data = "<body>")]
+pub async fn import_users(
+    key: ApiKey,
+    content_type: &ContentType,
+    limits: &Limits,
+    body: Data<'_>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<ImportReport>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let Some(format) = ImportFormat::from_content_type(content_type) else {
+            return Err(ApiError::new(
+                Status::UnsupportedMediaType,
+                "Import body must be text/csv or application/x-ndjson",
+            ));
+        };
+        let limit = limits.get("import").unwrap_or_else(|| 10.mebibytes());
+        let body = body
+            .open(limit)
+            .into_string()
+            .await
+            .map_err(|err| ApiError::new(Status::BadRequest, &err.to_string()))?;
+        if !body.is_complete() {
+            return Err(ApiError::new(
+                Status::PayloadTooLarge,
+                "Import body is too large",
+            ));
+        }
+
+        let rows = import::parse(&format, &body);
+        Ok(Json(db.import_users(rows).await?))
+    })
+    .await
+}
+
+/// Streams every user matching the filters as JSON Lines (the default) or
+/// CSV, one page at a time. Password hashes are left out unless
+/// `include_password_hashes` is set, which needs the `users:admin` scope.
+/// Dates are RFC 3339.
+#[get(
+    "/users/export?<format>&<created_after>&<created_before>&<logged_in>&<status>&<include_password_hashes>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn export_users<'r>(
+    format: Option<&str>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    logged_in: Option<bool>,
+    status: Option<&str>,
+    include_password_hashes: Option<bool>,
+    key: ApiKey,
+    db: &'r State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<(ContentType, TextStream![String + 'r]), ApiError> {
+    let include_password_hashes = include_password_hashes.unwrap_or(false);
+    let scope = if include_password_hashes {
+        Scope::UsersAdmin
+    } else {
+        Scope::UsersRead
+    };
+    let (format, filter) = verify_api_key(key, scope, db, verifier, || async {
+        let Some(format) = ExportFormat::from_name(format) else {
+            return Err(ApiError::new(
+                Status::BadRequest,
+                "Export format must be jsonl or csv",
+            ));
+        };
+        let status = match status.map(AccountStatus::from_name) {
+            Some(None) => {
+                return Err(ApiError::new(
+                    Status::BadRequest,
+                    "Account status must be pending, active, suspended, locked or deleted",
+                ))
+            }
+            status => status.flatten(),
+        };
+        let filter = ExportFilter {
+            created_after: export::parse_date(created_after)
+                .map_err(|err| ApiError::new(Status::BadRequest, &err))?,
+            created_before: export::parse_date(created_before)
+                .map_err(|err| ApiError::new(Status::BadRequest, &err))?,
+            logged_in,
+            status,
+            include_password_hashes,
+        };
+        Ok((format, filter))
+    })
+    .await?;
+
+    let content_type = format.content_type();
+    let stream = TextStream! {
+        let mut start = 0;
+        loop {
+            // Headers are already sent, so a failing page ends the stream early.
+            let Ok(users) = db.export_users(&filter, start).await else {
+                break;
+            };
+            let count = users.len();
+            let users: Vec<_> = users
+                .into_iter()
+                .map(|user| ExportedUser::new(user, filter.include_password_hashes))
+                .collect();
+            let Ok(page) = export::encode(&format, &users, start == 0) else {
+                break;
+            };
+            if !page.is_empty() {
+                yield page;
+            }
+            if count < export::EXPORT_BATCH_SIZE {
+                break;
+            }
+            start += count;
+        }
+    };
+    Ok((content_type, stream))
+}
+
+#[get("/users/<id>")]
+pub async fn get_user(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersRead, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        Ok::<_, ApiError>(Json(UserResponse::from(user)))
+    })
+    .await
+}
+
+/// Updates a user's username or locale. A different `email` is refused;
+/// email changes go through `POST /users/<id>/email`.
+#[patch("/users/<id>", data = "<update>")]
+pub async fn update_user(
+    id: String,
+    key: ApiKey,
+    update: Json<UserUpdate>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let key = key.or_legacy(update.api_key.clone());
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        require_user(db, id.clone()).await?;
+        match db.update_user(id, update.into_inner()).await? {
+            Some(user) => Ok(Json(UserResponse::from(user))),
+            None => Err(ApiError::not_found("User not found")),
+        }
+    })
+    .await
+}
+
+/// Renames a user. The old name is kept in their history and reserved for
+/// them for `username_reservation_days`.
+#[put("/users/<id>/username", data = "<change>")]
+pub async fn change_username(
+    id: String,
+    key: ApiKey,
+    change: Json<UsernameChange>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let user = require_user(db, id.clone()).await?;
+        let new_username = change.into_inner().new_username;
+        if new_username == user.username {
+            return Err(ApiError::new(
+                Status::BadRequest,
+                "New username is the same as the current one",
+            ));
+        }
+        let update = UserUpdate {
+            email: None,
+            username: Some(new_username),
+            locale: None,
+            api_key: None,
+        };
+        match db.update_user(id, update).await? {
+            Some(user) => Ok(Json(UserResponse::from(user))),
+            None => Err(ApiError::not_found("User not found")),
+        }
+    })
+    .await
+}
+
+/// A machine-readable archive of everything held about the user, for
+/// data subject access requests. Secrets are never included, and
+/// `not_recorded` names the kinds of data the service does not keep.
+#[get("/users/<id>/data")]
+pub async fn export_user_data(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserDataExport>, ApiError> {
+    verify_api_key(key, Scope::UsersRead, db, verifier, || async {
+        match db.user_data(id).await? {
+            Some(export) => Ok(Json(export)),
+            None => Err(ApiError::not_found("User not found")),
+        }
+    })
+    .await
+}
+
+/// The user's earlier usernames, oldest first.
+#[get("/users/<id>/username_history")]
+pub async fn username_history(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<Vec<PreviousUsernameResponse>>, ApiError> {
+    verify_api_key(key, Scope::UsersRead, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        Ok::<_, ApiError>(Json(
+            user.previous_usernames
+                .into_iter()
+                .map(PreviousUsernameResponse::from)
+                .collect(),
+        ))
+    })
+    .await
+}
+
+/// Schedules the user for deletion. The account stops working at once and
+/// can be restored until `deletion_grace_days` have passed.
+#[delete("/users/<id>")]
+pub async fn delete_user(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<NoContent, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        if require_user(db, id.clone()).await?.status == AccountStatus::Deleted {
+            return Err(ApiError::new(
+                Status::Conflict,
+                "User is already pending deletion",
+            ));
+        }
+        db.delete_user(id).await?;
+        Ok(NoContent)
+    })
+    .await
+}
+
+/// Changes a user's password after checking their current one. Sessions
+/// are a single flag per user, so `revoke_sessions` signs them out
+/// everywhere. The user is emailed a security notice.
+#[put("/users/<id>/password", data = "<change>")]
+pub async fn change_password(
+    id: String,
+    key: ApiKey,
+    change: Json<PasswordChange>,
+    db: &State<Database>,
+    mailer: &State<Mailer>,
+    verifier: &State<KeyVerifier>,
+) -> Result<NoContent, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let change = change.into_inner();
+        let user = require_user(db, id).await?;
+        let Some(user) = db.check_password(user, &change.current_password).await? else {
+            return Err(ApiError::unauthorized("Current password is incorrect"));
+        };
+
+        enforce_password_policy(db, &change.new_password, &user.username, &user.email).await?;
+        db.change_password(&user, change.new_password, change.revoke_sessions)
+            .await?;
+        if mailer.is_enabled() {
+            queue_email(
+                db,
+                mailer,
+                &user,
+                "security_notice",
+                &[
+                    ("username", &user.username),
+                    ("event", "Your password was changed."),
+                ],
+                None,
+            )
+            .await?;
+        }
+        Ok(NoContent)
+    })
+    .await
+}
+
+/// Starts moving a user to a new email address. The new address is sent a
+/// confirmation token and the current one a notice with a cancel token;
+/// the email only changes once the new address confirms. Without mail
+/// configured both tokens are returned to the caller.
+#[post("/users/<id>/email", data = "<request>")]
+pub async fn request_email_change(
+    id: String,
+    key: ApiKey,
+    request: Json<EmailChangeRequest>,
+    db: &State<Database>,
+    mailer: &State<Mailer>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Accepted<Json<EmailChangeIssued>>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let new_email = request.into_inner().new_email;
+        let user = require_user(db, id).await?;
+        if new_email == user.email {
+            return Err(ApiError::new(
+                Status::BadRequest,
+                "New email is the same as the current one",
+            ));
+        }
+
+        let (confirm_token, cancel_token) = (random_token(), random_token());
+        let expires_at = db
+            .start_email_change(
+                &user.email,
+                &new_email,
+                &verifier.digest(&email_change_input("confirm", &confirm_token)),
+                &verifier.digest(&email_change_input("cancel", &cancel_token)),
+            )
+            .await?;
+        if !mailer.is_enabled() {
+            return Ok(Accepted(Json(EmailChangeIssued {
+                new_email,
+                confirm_token: Some(confirm_token),
+                cancel_token: Some(cancel_token),
+                expires_at: expires_at.to_raw(),
+            })));
+        }
+
+        let expires_in = db.email_verification_ttl_minutes.to_string();
+        queue_email_to(
+            db,
+            mailer,
+            &user,
+            &new_email,
+            "email_change",
+            &[
+                ("username", &user.username),
+                ("new_email", &new_email),
+                ("code", &confirm_token),
+                ("expires_in_minutes", &expires_in),
+            ],
+            Some(&expires_at),
+        )
+        .await?;
+        queue_email(
+            db,
+            mailer,
+            &user,
+            "email_change_notice",
+            &[
+                ("username", &user.username),
+                ("new_email", &new_email),
+                ("code", &cancel_token),
+            ],
+            Some(&expires_at),
+        )
+        .await?;
+        Ok(Accepted(Json(EmailChangeIssued {
+            new_email,
+            confirm_token: None,
+            cancel_token: None,
+            expires_at: expires_at.to_raw(),
+        })))
+    })
+    .await
+}
+
+/// Moves the user to the address the confirm token was sent to, which
+/// counts as verifying it.
+#[post("/email_change/confirm", data = "<request>")]
+pub async fn confirm_email_change(
+    key: ApiKey,
+    request: Json<EmailChangeToken>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let token_hash = verifier.digest(&email_change_input("confirm", &request.token));
+        match db.confirm_email_change(&token_hash).await? {
+            Some(user) => Ok(Json(UserResponse::from(user))),
+            None => Err(ApiError::new(
+                Status::BadRequest,
+                "Confirmation token is invalid or expired",
+            )),
+        }
+    })
+    .await
+}
+
+/// Abandons a pending email change using the token sent to the old address.
+#[post("/email_change/cancel", data = "<request>")]
+pub async fn cancel_email_change(
+    key: ApiKey,
+    request: Json<EmailChangeToken>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<NoContent, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let token_hash = verifier.digest(&email_change_input("cancel", &request.token));
+        match db.cancel_email_change(&token_hash).await? {
+            Some(_) => Ok(NoContent),
+            None => Err(ApiError::new(Status::BadRequest, "Cancel token is invalid")),
+        }
+    })
+    .await
+}
+
+/// Cancels a pending deletion within its grace period.
+#[post("/users/<id>/restore")]
+pub async fn restore_user(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        match db.restore_user(&user).await? {
+            Some(user) => Ok(Json(UserResponse::from(user))),
+            None => Err(ApiError::new(
+                Status::Conflict,
+                "User is not pending deletion or can no longer be restored",
+            )),
+        }
+    })
+    .await
+}
+
+/// Moves `user` to status `to` on an admin's behalf, recording `reason`.
+async fn change_status(
+    db: &Database,
+    user: &User,
+    to: AccountStatus,
+    reason: &str,
+) -> Result<Json<UserResponse>, ApiError> {
+    if reason.trim().is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "A reason is required"));
+    }
+    if !user.status.can_become(to) {
+        return Err(ApiError::new(
+            Status::Conflict,
+            &format!(
+                "Cannot change account status from {} to {}",
+                user.status.as_str(),
+                to.as_str()
+            ),
+        ));
+    }
+    match db.set_status(user, to, reason).await? {
+        Some(user) => Ok(Json(UserResponse::from(user))),
+        None => Err(ApiError::new(
+            Status::Conflict,
+            "Account status changed meanwhile, try again",
+        )),
+    }
+}
+
+/// Blocks the user from signing in and signs them out.
+#[post("/users/<id>/suspend", data = "<request>")]
+pub async fn suspend_user(
+    id: String,
+    key: ApiKey,
+    request: Json<StatusChangeRequest>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersAdmin, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        change_status(db, &user, AccountStatus::Suspended, &request.reason).await
+    })
+    .await
+}
+
+/// Lifts a suspension, returning the user to `active`, or `pending` if
+/// their email is unverified.
+#[post("/users/<id>/unsuspend", data = "<request>")]
+pub async fn unsuspend_user(
+    id: String,
+    key: ApiKey,
+    request: Json<StatusChangeRequest>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersAdmin, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        if user.status != AccountStatus::Suspended {
+            return Err(ApiError::new(Status::Conflict, "User is not suspended"));
+        }
+        let to = AccountStatus::usable(user.verified);
+        change_status(db, &user, to, &request.reason).await
+    })
+    .await
+}
+
+/// Lifts a failed-login lock before it expires.
+#[post("/users/<id>/unlock", data = "<request>")]
+pub async fn unlock_user(
+    id: String,
+    key: ApiKey,
+    request: Json<StatusChangeRequest>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersAdmin, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        if user.status != AccountStatus::Locked {
+            return Err(ApiError::new(Status::Conflict, "User is not locked"));
+        }
+        let to = AccountStatus::usable(user.verified);
+        change_status(db, &user, to, &request.reason).await
+    })
+    .await
+}
+
+/// Every status the user has held, oldest first, with reasons.
+#[get("/users/<id>/status_history")]
+pub async fn status_history(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<Vec<StatusChangeResponse>>, ApiError> {
+    verify_api_key(key, Scope::UsersRead, db, verifier, || async {
+        let user = require_user(db, id).await?;
+        Ok::<_, ApiError>(Json(
+            user.status_history
+                .into_iter()
+                .map(StatusChangeResponse::from)
+                .collect(),
+        ))
+    })
+    .await
+}
+
+#[post("/sessions", data = "<credentials>")]
+pub async fn create_session(
+    key: ApiKey,
+    credentials: Json<NewSession>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Created<Json<LoginSuccess>>, ApiError> {
+    let key = key.or_legacy(credentials.api_key.clone());
+    verify_api_key(key, Scope::SessionsWrite, db, verifier, || async {
+        let credentials = credentials.into_inner();
+        let login = match (credentials.email, credentials.username) {
+            (Some(email), _) => {
+                db.email_login(EmailLogin {
+                    email,
+                    password: credentials.password,
+                    api_key: credentials.api_key,
+                })
+                .await?
+            }
+            (None, Some(username)) => {
+                db.username_login(UsernameLogin {
+                    username,
+                    password: credentials.password,
+                    api_key: credentials.api_key,
+                })
+                .await?
+            }
+            (None, None) => {
+                return Err(ApiError::new(
+                    Status::BadRequest,
+                    "Email or username is required",
+                ))
+            }
+        };
+        Ok(Created::new(format!("/v1/sessions/{}", login.username)).body(Json(login)))
+    })
+    .await
+}
+
+#[delete("/sessions/<id>")]
+pub async fn delete_session(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<NoContent, ApiError> {
+    verify_api_key(key, Scope::SessionsWrite, db, verifier, || async {
+        if !require_user(db, id.clone()).await?.logged_in {
+            return Err(ApiError::not_found("Session not found"));
+        }
+        db.signout(id).await?;
+        Ok(NoContent)
+    })
+    .await
+}
+
+/// Issues a six-digit recovery code for the account, replacing any earlier
+/// one, and emails it to the user. Without a mail transport the code is
+/// returned once for the caller to deliver. Only a keyed digest of it is
+/// stored. A new code is issued at most once per `recovery_resend_seconds`.
+/// The response is 202 whether or not the account exists or a code was
+/// issued, so it cannot be used to find accounts.
+#[post("/recovery", data = "<request>")]
+pub async fn request_recovery(
+    key: ApiKey,
+    request: Json<RecoveryRequest>,
+    db: &State<Database>,
+    mailer: &State<Mailer>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Accepted<Json<RecoveryIssued>>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let request = request.into_inner();
+        let Some(user) = find_account(db, request.email, request.username).await? else {
+            return Ok::<_, ApiError>(Accepted(Json(RecoveryIssued::default())));
+        };
+
+        let code = rand::thread_rng().gen_range(100_000..1_000_000).to_string();
+        let code_hash = verifier.digest(&recovery_code_input(&user.email, &code));
+        let Some(expires_at) = db.start_recovery(&user.email, &code_hash).await? else {
+            return Ok(Accepted(Json(RecoveryIssued::default())));
+        };
+        if !mailer.is_enabled() {
+            return Ok(Accepted(Json(RecoveryIssued {
+                code: Some(code),
+                expires_at: Some(expires_at.to_raw()),
+            })));
+        }
+
+        let expires_in = db.recovery_code_ttl_minutes.to_string();
+        queue_email(
+            db,
+            mailer,
+            &user,
+            "recovery",
+            &[
+                ("username", &user.username),
+                ("code", &code),
+                ("expires_in_minutes", &expires_in),
+            ],
+            Some(&expires_at),
+        )
+        .await?;
+        Ok(Accepted(Json(RecoveryIssued {
+            code: None,
+            expires_at: Some(expires_at.to_raw()),
+        })))
+    })
+    .await
+}
+
+/// Marks the account the token was issued to as verified. Tokens are
+/// single use.
+#[post("/verify_email", data = "<request>")]
+pub async fn verify_email(
+    key: ApiKey,
+    request: Json<VerifyEmail>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<UserResponse>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let token_hash = verifier.digest(&verification_token_input(&request.token));
+        match db.verify_email(&token_hash).await? {
+            Some(user) => Ok(Json(UserResponse::from(user))),
+            None => Err(ApiError::new(
+                Status::BadRequest,
+                "Verification token is invalid or expired",
+            )),
+        }
+    })
+    .await
+}
+
+/// Issues a new verification token, replacing any earlier one, at most once
+/// per `email_verification_resend_seconds`. When the server cannot send
+/// mail the token is returned to the caller.
+#[post("/verify_email/resend", data = "<request>")]
+pub async fn resend_verification(
+    key: ApiKey,
+    request: Json<VerificationResend>,
+    db: &State<Database>,
+    mailer: &State<Mailer>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Accepted<Json<VerificationIssued>>, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let request = request.into_inner();
+        let Some(user) = find_account(db, request.email, request.username).await? else {
+            return Err(ApiError::not_found("User not found"));
+        };
+        if user.verified {
+            return Err(ApiError::new(
+                Status::Conflict,
+                "Email address is already verified",
+            ));
+        }
+        let Some((token, expires_at)) = issue_verification(db, mailer, verifier, &user).await?
+        else {
+            return Err(ApiError::new(
+                Status::TooManyRequests,
+                "A verification email was sent recently, try again later",
+            ));
+        };
+        Ok(Accepted(Json(VerificationIssued {
+            token: (!mailer.is_enabled()).then_some(token),
+            expires_at: expires_at.to_raw(),
+        })))
+    })
+    .await
+}
+
+/// Sets a new password with a recovery code. Each try counts against the
+/// code; once it expires or runs out of attempts it is cleared and a new
+/// one must be requested. Success signs the user out.
+#[post("/recovery/reset", data = "<reset>")]
+pub async fn reset_password(
+    key: ApiKey,
+    reset: Json<PasswordReset>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<NoContent, ApiError> {
+    verify_api_key(key, Scope::UsersWrite, db, verifier, || async {
+        let reset = reset.into_inner();
+        let invalid = || ApiError::new(Status::BadRequest, "Recovery code is invalid or expired");
+        let Some(user) = find_account(db, reset.email, reset.username).await? else {
+            return Err(invalid());
+        };
+        let Some(recovery) = &user.recovery else {
+            return Err(invalid());
+        };
+
+        let attempts = db.count_recovery_attempt(&user.email).await?;
+        if *recovery.expires_at <= *Datetime::default() || attempts > db.recovery_max_attempts {
+            db.clear_recovery(&user.email).await?;
+            return Err(invalid());
+        }
+        let input = recovery_code_input(&user.email, &reset.code);
+        if !verifier.matches(&input, &recovery.code_hash) {
+            return Err(invalid());
+        }
+
+        enforce_password_policy(db, &reset.new_password, &user.username, &user.email).await?;
+        db.reset_password(&user, reset.new_password).await?;
+        Ok(NoContent)
+    })
+    .await
+}
+
+#[post("/api_keys", data = "<request>")]
+pub async fn create_api_key(
+    key: ApiKey,
+    request: Json<NewApiKey>,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Created<Json<CreatedApiKey>>, ApiError> {
+    verify_api_key(key, Scope::KeysAdmin, db, verifier, || async {
+        let (key_id, key, key_hash) = verifier.issue();
+        match db
+            .create_api_key(request.into_inner(), &key_id, &key_hash)
+            .await?
+        {
+            Some(record) => Ok(Created::new(format!("/v1/api_keys/{key_id}")).body(Json(
+                CreatedApiKey {
+                    key,
+                    details: ApiKeyResponse::from(record),
+                },
+            ))),
+            None => Err(ApiError::new(
+                Status::InternalServerError,
+                "An error occured",
+            )),
+        }
+    })
+    .await
+}
+
+#[get("/api_keys")]
+pub async fn list_api_keys(
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<Vec<ApiKeyResponse>>, ApiError> {
+    verify_api_key(key, Scope::KeysAdmin, db, verifier, || async {
+        let keys = db.list_api_keys().await?;
+        Ok::<_, ApiError>(Json(keys.into_iter().map(ApiKeyResponse::from).collect()))
+    })
+    .await
+}
+
+#[delete("/api_keys/<id>")]
+pub async fn revoke_api_key(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<NoContent, ApiError> {
+    verify_api_key(key, Scope::KeysAdmin, db, verifier, || async {
+        match db.revoke_api_key(id).await? {
+            Some(_) => Ok(NoContent),
+            None => Err(ApiError::not_found("Api key not found")),
+        }
+    })
+    .await
+}
+
+/// Lists queued emails, newest first, optionally only those with `status`.
+/// Message bodies are never returned.
+#[get("/emails?<status>")]
+pub async fn list_emails(
+    status: Option<&str>,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Json<Vec<QueuedEmailResponse>>, ApiError> {
+    verify_api_key(key, Scope::EmailsAdmin, db, verifier, || async {
+        let status = match status.map(EmailStatus::from_name) {
+            Some(None) => {
+                return Err(ApiError::new(
+                    Status::BadRequest,
+                    "Email status must be pending, sent or dead",
+                ))
+            }
+            status => status.flatten(),
+        };
+        let emails = db.list_emails(status).await?;
+        Ok(Json(
+            emails.into_iter().map(QueuedEmailResponse::from).collect(),
+        ))
+    })
+    .await
+}
+
+/// Puts a dead-lettered email back in the queue with its attempts reset.
+/// Emails whose code expired or died undelivered are gone for good.
+#[post("/emails/<id>/retry")]
+pub async fn retry_email(
+    id: String,
+    key: ApiKey,
+    db: &State<Database>,
+    verifier: &State<KeyVerifier>,
+) -> Result<Accepted<Json<QueuedEmailResponse>>, ApiError> {
+    verify_api_key(key, Scope::EmailsAdmin, db, verifier, || async {
+        match db.retry_email(id).await? {
+            Some(email) => Ok(Accepted(Json(QueuedEmailResponse::from(email)))),
+            None => Err(ApiError::not_found(
+                "No dead-lettered email with that id that can still be sent",
+            )),
+        }
+    })
+    .await
+}
@@ -0,0 +1,68 @@
+//! Tokens and codes mailed to users: email verification tokens, email
+//! change confirm and cancel tokens, and account recovery codes. Only keyed
+//! digests of them are stored, over inputs built here.
+
+use crate::{
+    api_key::KeyVerifier, database::Database, email_queue::queue_email, error::ApiError,
+    mailer::Mailer, models::User,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use surrealdb::sql::Datetime;
+
+/// What an email verification token digest covers. Tokens are long enough
+/// to look accounts up by digest alone, so links need only the token.
+pub fn verification_token_input(token: &str) -> String {
+    format!("verify_email:{token}")
+}
+
+pub fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// What the digests of an email change's confirm and cancel tokens cover.
+pub fn email_change_input(action: &str, token: &str) -> String {
+    format!("email_change:{action}:{token}")
+}
+
+/// Issues a verification token for `user` and, when mail is configured,
+/// queues it to them. Returns the token and its expiry, or `None` if the
+/// account is already verified or was sent a token too recently.
+pub async fn issue_verification(
+    db: &Database,
+    mailer: &Mailer,
+    verifier: &KeyVerifier,
+    user: &User,
+) -> Result<Option<(String, Datetime)>, ApiError> {
+    let token = random_token();
+    let token_hash = verifier.digest(&verification_token_input(&token));
+    let Some(expires_at) = db.start_verification(&user.email, &token_hash).await? else {
+        return Ok(None);
+    };
+    if mailer.is_enabled() {
+        let expires_in = db.email_verification_ttl_minutes.to_string();
+        queue_email(
+            db,
+            mailer,
+            user,
+            "verification",
+            &[
+                ("username", &user.username),
+                ("code", &token),
+                ("expires_in_minutes", &expires_in),
+            ],
+            Some(&expires_at),
+        )
+        .await?;
+    }
+    Ok(Some((token, expires_at)))
+}
+
+/// What a recovery code digest covers, so a code only ever verifies for
+/// the account it was issued to.
+pub fn recovery_code_input(email: &str, code: &str) -> String {
+    format!("recovery:{email}:{code}")
+}